@@ -1,8 +1,147 @@
 use fuse::*;
+use std::error::Error;
 use std::ffi::OsStr;
+use std::fmt;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::convert::TryFrom;
 use time::Timespec;
-use libc::ENOENT;
+use libc::{c_int, ENOENT, EIO, EINVAL, ENODATA, ERANGE};
 use byteorder::*;
+use crate::compression::*;
+use crate::payload::BlobData;
+
+pub const LEGACY_MAGIC: &[u8; 6] = b"rpack0";
+pub const BLOB_MAGIC: &[u8; 6] = b"rpackv";
+pub const FORMAT_VERSION: u16 = 1;
+
+// feature bits a reader has to understand to make sense of the blob, unknown bits are refused
+pub const FEATURE_COMPRESSION: u64 = 1 << 0; // file entries carry a codec and their uncompressed size
+pub const FEATURE_BLOCKS: u64 = 1 << 1; // file entries carry their uncompressed size and a block index
+pub const FEATURE_SYMLINKS: u64 = 1 << 2; // directories can hold symlinks, which live in their own section
+pub const FEATURE_NAMES: u64 = 1 << 3; // some directories name their children themselves, for hard links
+pub const FEATURE_FULL_ATTRIBUTES: u64 = 1 << 4; // attribute records carry blocks, crtime, nlink, uid, gid, rdev and flags
+pub const FEATURE_XATTRS: u64 = 1 << 5; // extended attributes of some nodes live in their own section
+pub const FEATURE_SPECIAL_FILES: u64 = 1 << 6; // directories can hold fifos, devices and sockets, which live in their own section
+pub const FEATURE_MANIFEST: u64 = 1 << 7; // the package names its entrypoint and describes itself in a manifest section
+pub const SUPPORTED_FEATURES: u64 = FEATURE_COMPRESSION | FEATURE_BLOCKS | FEATURE_SYMLINKS | FEATURE_NAMES | FEATURE_FULL_ATTRIBUTES | FEATURE_XATTRS | FEATURE_SPECIAL_FILES | FEATURE_MANIFEST;
+
+// header flags, none are defined yet so any set bit comes from a newer writer
+pub const SUPPORTED_FLAGS: u32 = 0;
+
+// owner of everything in blobs that did not record it
+pub const LEGACY_UID: u32 = 501;
+pub const LEGACY_GID: u32 = 20;
+
+// symlinks followed while resolving a path before giving up, what Linux allows before ELOOP
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+// decompressed blocks kept around for reads, 32 blocks of the default size is 4 MiB
+pub const BLOCK_CACHE_SIZE: usize = 32;
+
+pub const SECTION_DIRECTORIES: u32 = 1;
+pub const SECTION_FILES: u32 = 2;
+pub const SECTION_ATTRIBUTES: u32 = 3;
+pub const SECTION_DATA: u32 = 4;
+pub const SECTION_SYMLINKS: u32 = 5;
+pub const SECTION_NAMES: u32 = 6;
+pub const SECTION_XATTRS: u32 = 7;
+pub const SECTION_SPECIAL_FILES: u32 = 8;
+pub const SECTION_MANIFEST: u32 = 9;
+
+// what packages without a manifest run
+pub const DEFAULT_ENTRYPOINT: &str = "startup.sh";
+
+// what a directory's node_types say about its nodes
+pub const NODE_DIRECTORY: u8 = 0;
+pub const NODE_FILE: u8 = 1;
+pub const NODE_SYMLINK: u8 = 2;
+pub const NODE_SPECIAL: u8 = 3; // fifos, devices and sockets, anything without contents of its own
+
+// magic, version, flags, features and the number of sections
+pub const HEADER_SIZE: usize = 6 + 2 + 4 + 8 + 4;
+// kind, offset and length
+pub const SECTION_ENTRY_SIZE: usize = 4 + 8 + 8;
+
+// who owns the files once they are mounted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ownership {
+    Preserve, // the ids recorded when the package was generated
+    User, // whoever mounts the package
+    Fixed(u32, u32),
+}
+
+impl Ownership {
+    // preserve, user or uid:gid
+    pub fn from_name(name: &str) -> Option<Ownership> {
+        match name {
+            "preserve" => Some(Ownership::Preserve),
+            "user" => Some(Ownership::User),
+            _ => {
+                let (uid, gid) = name.split_once(':')?;
+                Some(Ownership::Fixed(uid.parse().ok()?, gid.parse().ok()?))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlobError {
+    Truncated { offset: usize, needed: usize },
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnsupportedFeatures(u64),
+    UnsupportedFlags(u32),
+    MissingSection(u32),
+    InvalidUtf8Name { offset: usize },
+    DanglingNode { node: u64 },
+    InvalidTimestamp { offset: usize },
+    UnknownCodec { offset: usize },
+    UnknownKind { offset: usize },
+    BadBlockIndex { offset: usize },
+    MissingRoot,
+    LengthOverflow { offset: usize },
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlobError::Truncated { offset, needed } => write!(f, "blob is truncated, needed {} bytes at offset {}", needed, offset),
+            BlobError::BadMagic => write!(f, "not an rpackage blob"),
+            BlobError::UnsupportedVersion(version) => write!(f, "unsupported blob format version {}", version),
+            BlobError::UnsupportedFeatures(features) => write!(f, "blob uses unsupported features {:#x}", features),
+            BlobError::UnsupportedFlags(flags) => write!(f, "blob sets unsupported flags {:#x}", flags),
+            BlobError::MissingSection(kind) => write!(f, "blob is missing section {}", kind),
+            BlobError::InvalidUtf8Name { offset } => write!(f, "name at offset {} is not valid UTF-8", offset),
+            BlobError::DanglingNode { node } => write!(f, "node {} is referenced but never defined", node),
+            BlobError::InvalidTimestamp { offset } => write!(f, "timestamp at offset {} is out of range", offset),
+            BlobError::UnknownCodec { offset } => write!(f, "unknown compression codec at offset {}", offset),
+            BlobError::UnknownKind { offset } => write!(f, "unknown file type at offset {}", offset),
+            BlobError::BadBlockIndex { offset } => write!(f, "block index at offset {} does not match its file", offset),
+            BlobError::MissingRoot => write!(f, "blob has no root directory"),
+            BlobError::LengthOverflow { offset } => write!(f, "length at offset {} is out of range", offset),
+        }
+    }
+}
+
+impl Error for BlobError {}
+
+#[derive(Clone, Copy)]
+pub struct BlobSection {
+    pub kind: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone)]
+pub struct BlobHeader {
+    pub version: u16,
+    pub flags: u32,
+    pub features: u64,
+    pub sections: Vec<BlobSection>,
+}
 
 #[derive(Clone)]
 pub struct FuseDirectory {
@@ -11,83 +150,327 @@ pub struct FuseDirectory {
     pub node_types: Vec<u8>,
     pub node: u64,
     pub is_root: bool,
-    pub parent_node: u64
+    pub parent_node: u64,
+    pub names: Vec<String>, // one per node when set, a hard link can have a different name in every directory
 }
 
 #[derive(Clone)]
 pub struct FuseFile {
     pub name: String,
-    pub data: Vec<u8>,
+    pub data_offset: u64, // where the stored, possibly compressed, contents start in FuseStructure::data
+    pub data_length: u64,
+    pub node: u64,
+    pub size: u64, // uncompressed size
+    pub block_size: u64,
+    pub blocks: Vec<Block>,
+}
+
+// (node, block index) and the decompressed block
+pub type CachedBlock = ((u64, usize), Arc<Vec<u8>>);
+
+#[derive(Clone)]
+pub struct FuseSymlink {
+    pub name: String,
     pub node: u64,
+    pub target: String,
 }
 
+#[derive(Clone)]
+pub struct FuseSpecial {
+    pub name: String,
+    pub node: u64,
+    pub kind: FileType, // the device numbers are in its attributes' rdev
+}
+
+// what the package says about itself, written as key/value pairs so new keys don't need a new section
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub entrypoint: String, // path inside the package
+    pub interpreter: String, // runs the entrypoint when set, otherwise it is executed itself
+    pub args: Vec<String>, // given to the entrypoint before the user's own arguments
+    pub workdir: String, // where inside the package the entrypoint starts, empty to keep the caller's working directory
+    pub env: Vec<(String, String)>,
+    pub labels: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+pub struct BlockCache {
+    pub capacity: usize,
+    pub entries: VecDeque<CachedBlock>, // most recently used first
+}
+
+// positions in FuseStructure's vectors by inode, and every directory's children by name
+#[derive(Clone, Default)]
+pub struct NodeIndex {
+    pub root: u64,
+    pub directories: HashMap<u64, usize>,
+    pub files: HashMap<u64, usize>,
+    pub symlinks: HashMap<u64, usize>,
+    pub specials: HashMap<u64, usize>,
+    pub attributes: HashMap<u64, usize>,
+    pub children: HashMap<u64, HashMap<String, u64>>,
+}
+
+// the name of an extended attribute and its value
+pub type Xattr = (String, Vec<u8>);
+
 #[derive(Clone)]
 pub struct FuseStructure {
     pub epoch: Timespec,
     pub directories: Vec<FuseDirectory>,
     pub files: Vec<FuseFile>,
-    pub attributes: Vec<FileAttr>
+    pub symlinks: Vec<FuseSymlink>,
+    pub specials: Vec<FuseSpecial>,
+    pub attributes: Vec<FileAttr>,
+    pub xattrs: HashMap<u64, Vec<Xattr>>, // only nodes that have any
+    pub manifest: Manifest,
+    pub data: BlobData, // the blob the structure was read from, file contents are sliced out of it on demand
+    pub cache: BlockCache,
+    pub index: NodeIndex, // rebuilt by build_index whenever the vectors change
+    pub on_init: Option<Sender<()>>, // told once the kernel has initialized the mount
+    pub on_unmount: Option<Sender<()>>, // never sent on, its receiver sees it close once the session ends and drops the structure
 }
 
-pub trait FuseCommon<T> {
-    fn find_by_node(container:&Vec<T>, node:u64) -> Option<&T>;
-    fn serialize(&self) -> Vec<u8>;
-    fn deserialize(start:usize, data:&Vec<u8>) -> (T, u64); // returns type and read bytes
+// what read needs from fuse's ReplyData, fuse doesn't let replies be built outside of a session
+pub trait DataReply {
+    fn data(self, data: &[u8]);
+    fn error(self, err: c_int);
 }
 
-impl FuseCommon<FuseFile> for FuseFile {
-    fn find_by_node(container:&Vec<FuseFile>, node:u64) -> Option<&FuseFile> {
-        for file in container {
-            if file.node == node {
-                return Some(file);
-            }
-        }
+impl DataReply for ReplyData {
+    fn data(self, data: &[u8]) {
+        ReplyData::data(self, data);
+    }
 
-        return None;
+    fn error(self, err: c_int) {
+        ReplyData::error(self, err);
     }
+}
 
-    fn serialize(&self) -> Vec<u8> {
-        let mut returned:Vec<u8> = vec!();
+// the same for ReplyXattr, which can also answer with just the size of the value
+pub trait XattrReply {
+    fn size(self, size: u32);
+    fn data(self, data: &[u8]);
+    fn error(self, err: c_int);
+}
 
-        let name_size = self.name.len();
-        let name = &self.name;
+impl XattrReply for ReplyXattr {
+    fn size(self, size: u32) {
+        ReplyXattr::size(self, size);
+    }
 
-        returned.extend(name_size.to_be_bytes().to_vec());
-        returned.extend(name.as_bytes().to_vec());
+    fn data(self, data: &[u8]) {
+        ReplyXattr::data(self, data);
+    }
 
-        returned.extend(self.node.to_be_bytes().to_vec());
+    fn error(self, err: c_int) {
+        ReplyXattr::error(self, err);
+    }
+}
 
-        let file_size = self.data.len();
-        returned.extend(file_size.to_be_bytes().to_vec());
+pub trait FuseCommon<T> {
+    fn deserialize(start:usize, data:&[u8]) -> Result<(T, u64), BlobError>; // returns type and read bytes
+}
 
-        returned.extend(&self.data);
+impl FuseFile {
+    // rpack0 file entries carry their contents inline, right after the entry
+    pub fn deserialize_rpack0(start:usize, data: &[u8]) -> Result<(FuseFile, u64), BlobError> {
+        let mut bytes_read:usize = start;
 
-        returned
+        let name_size = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        let name = FuseStructure::read_name(data, bytes_read, name_size)?;
+        bytes_read += name_size;
+
+        let node = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
+
+        let file_size = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        FuseStructure::get_slice(data, bytes_read, file_size)?;
+        let data_offset = bytes_read as u64;
+        bytes_read += file_size;
+
+        Ok((FuseFile::single_block(name, node, data_offset, file_size as u64, Codec::Store, file_size as u64), (bytes_read - start) as u64))
     }
 
-    fn deserialize(start:usize, data: &Vec<u8>) -> (FuseFile, u64) {
+    pub fn deserialize_entry(start:usize, data: &[u8], data_section: &BlobSection, features: u64) -> Result<(FuseFile, u64), BlobError> {
         let mut bytes_read:usize = start;
 
-        let name_size = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let name_size = FuseStructure::read_length(data, bytes_read)?;
         bytes_read += 8;
-        let name = String::from_utf8(FuseStructure::get_sclice_from_vector(data, bytes_read, name_size as usize).to_vec()).unwrap();
-        bytes_read += name_size as usize;
+        let name = FuseStructure::read_name(data, bytes_read, name_size)?;
+        bytes_read += name_size;
 
-        let node = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let node = FuseStructure::read_u64(data, bytes_read)?;
         bytes_read += 8;
 
-        let file_size = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let data_offset = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        let data_length = FuseStructure::read_length(data, bytes_read)?;
         bytes_read += 8;
-        let file_data = FuseStructure::get_sclice_from_vector(data, bytes_read, file_size as usize);
-        bytes_read += file_size as usize;
 
-        (FuseFile {
+        let section = FuseStructure::get_section(data, data_section)?;
+        if FuseStructure::get_slice(section, data_offset, data_length).is_err() {
+            return Err(BlobError::Truncated { offset: data_section.offset as usize + data_offset, needed: data_length });
+        }
+        // from here on the offset is into the whole blob
+        let data_offset = data_section.offset + data_offset as u64;
+
+        if features & FEATURE_BLOCKS != 0 {
+            let index_start = bytes_read;
+            let size = FuseStructure::read_u64(data, bytes_read)?;
+            bytes_read += 8;
+            let block_size = FuseStructure::read_u64(data, bytes_read)?;
+            bytes_read += 8;
+            if block_size == 0 || block_size > MAX_BLOCK_SIZE as u64 {
+                return Err(BlobError::BadBlockIndex { offset: index_start });
+            }
+
+            let number_blocks = size.div_ceil(block_size);
+            FuseStructure::get_slice(data, bytes_read, (number_blocks as usize).saturating_mul(9))?;
+
+            let mut blocks: Vec<Block> = Vec::with_capacity(number_blocks as usize);
+            let mut offset: u64 = 0;
+            for _ in 0..number_blocks {
+                let length = FuseStructure::read_u64(data, bytes_read)?;
+                bytes_read += 8;
+                let codec = Codec::from_u8(FuseStructure::read_u8(data, bytes_read)?).ok_or(BlobError::UnknownCodec { offset: bytes_read })?;
+                bytes_read += 1;
+
+                blocks.push(Block {
+                    offset,
+                    length,
+                    codec,
+                });
+                offset = offset.checked_add(length).ok_or(BlobError::BadBlockIndex { offset: index_start })?;
+            }
+            if offset != data_length as u64 {
+                return Err(BlobError::BadBlockIndex { offset: index_start });
+            }
+
+            return Ok((FuseFile {
+                name,
+                node,
+                data_offset,
+                data_length: data_length as u64,
+                size,
+                block_size,
+                blocks,
+            }, (bytes_read - start) as u64));
+        }
+
+        // files written before block compression are a single block covering the whole file
+        let mut codec = Codec::Store;
+        let mut size = data_length as u64;
+        if features & FEATURE_COMPRESSION != 0 {
+            codec = Codec::from_u8(FuseStructure::read_u8(data, bytes_read)?).ok_or(BlobError::UnknownCodec { offset: bytes_read })?;
+            bytes_read += 1;
+            size = FuseStructure::read_u64(data, bytes_read)?;
+            // the whole file is decompressed at once, so its size is bounded like a block's
+            let limit = if codec == Codec::Store { data_length as u64 } else { MAX_BLOCK_SIZE as u64 };
+            if size > limit {
+                return Err(BlobError::LengthOverflow { offset: bytes_read });
+            }
+            bytes_read += 8;
+        }
+
+        Ok((FuseFile::single_block(name, node, data_offset, data_length as u64, codec, size), (bytes_read - start) as u64))
+    }
+
+    pub fn single_block(name: String, node: u64, data_offset: u64, data_length: u64, codec: Codec, size: u64) -> FuseFile {
+        let block = Block {
+            offset: 0,
+            length: data_length,
+            codec,
+        };
+
+        FuseFile {
             name,
             node,
-            data: file_data
-        }, (bytes_read - start) as u64)
+            data_offset,
+            data_length,
+            size,
+            block_size: size.max(1),
+            blocks: vec!(block),
+        }
+    }
+
+    // the stored contents of this file inside of the blob it belongs to
+    pub fn stored<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.data_offset as usize..(self.data_offset + self.data_length) as usize]
+    }
+
+    fn read_block(&self, data: &[u8], index: usize) -> io::Result<Vec<u8>> {
+        let block = &self.blocks[index];
+        let start = index as u64 * self.block_size;
+        let size = self.block_size.min(self.size - start);
+        let stored = &self.stored(data)[block.offset as usize..(block.offset + block.length) as usize];
+        decompress(block.codec, stored, size as usize)
     }
 
+    pub fn read_data(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut returned: Vec<u8> = Vec::with_capacity(self.size as usize);
+        for index in 0..self.blocks.len() {
+            returned.extend(self.read_block(data, index)?);
+        }
+        Ok(returned)
+    }
+
+    // only the blocks covering offset..offset + size are decompressed, going through the cache
+    pub fn read_range(&self, data: &[u8], offset: u64, size: u64, cache: &mut BlockCache) -> io::Result<Vec<u8>> {
+        let end = self.size.min(offset.saturating_add(size));
+        let mut returned: Vec<u8> = vec!();
+        if offset >= end {
+            return Ok(returned);
+        }
+
+        let first = (offset / self.block_size) as usize;
+        let last = ((end - 1) / self.block_size) as usize;
+        for index in first..=last {
+            let block = match cache.get(self.node, index) {
+                Some(block) => block,
+                None => cache.insert(self.node, index, self.read_block(data, index)?)
+            };
+
+            let block_start = index as u64 * self.block_size;
+            let from = offset.max(block_start) - block_start;
+            let to = end.min(block_start + block.len() as u64) - block_start;
+            returned.extend(&block[from as usize..to as usize]);
+        }
+        Ok(returned)
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&mut self, node: u64, index: usize) -> Option<Arc<Vec<u8>>> {
+        let position = self.entries.iter().position(|(key, _)| *key == (node, index))?;
+        let entry = self.entries.remove(position)?;
+        let block = entry.1.clone();
+        self.entries.push_front(entry);
+        Some(block)
+    }
+
+    pub fn insert(&mut self, node: u64, index: usize, block: Vec<u8>) -> Arc<Vec<u8>> {
+        let block = Arc::new(block);
+        if self.capacity == 0 {
+            return block;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(((node, index), block.clone()));
+        block
+    }
 }
 
 impl FuseDirectory {
@@ -112,169 +495,188 @@ impl FuseDirectory {
 }
 
 impl FuseCommon<FuseDirectory> for FuseDirectory {
-    fn find_by_node(container:&Vec<FuseDirectory>, node:u64) -> Option<&FuseDirectory> {
-        for directory in container {
-            if directory.node == node {
-                return Some(directory);
-            }
-        }
-        None
-    }
-
-    fn serialize(&self) -> Vec<u8> {
-        let mut returned:Vec<u8> = vec!();
-
-        let name_size = self.name.len();
-        let name = &self.name;
-
-        returned.extend(name_size.to_be_bytes().to_vec());
-        returned.extend(name.as_bytes().to_vec());
-
-        returned.extend(self.node.to_be_bytes().to_vec());
-
-        let nodes_size = self.nodes.len();
-        returned.extend(nodes_size.to_be_bytes().to_vec());
-
-        for node in &self.nodes {
-            returned.extend(node.to_be_bytes().to_vec());
-        }
-
-        for node_type in &self.node_types {
-            returned.push(*node_type);
-        }
-
-        returned.extend(self.parent_node.to_be_bytes().to_vec());
-
-        if self.is_root {
-            returned.push(1);
-        } else {
-            returned.push(0);
-        }
-
-        returned
-    }
-
-    fn deserialize(start:usize, data:&Vec<u8>) -> (FuseDirectory, u64) {
+    fn deserialize(start:usize, data:&[u8]) -> Result<(FuseDirectory, u64), BlobError> {
         let mut bytes_read:usize = start;
 
-        let name_size = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let name_size = FuseStructure::read_length(data, bytes_read)?;
         bytes_read += 8;
-        let name = String::from_utf8_lossy(FuseStructure::get_sclice_from_vector(data, bytes_read, name_size as usize).as_slice()).into_owned();
-        bytes_read += name_size as usize;
+        let name = FuseStructure::read_name(data, bytes_read, name_size)?;
+        bytes_read += name_size;
 
-        let node = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let node = FuseStructure::read_u64(data, bytes_read)?;
         bytes_read += 8;
 
-        let nodes_size = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let nodes_size = FuseStructure::read_length(data, bytes_read)?;
         bytes_read += 8;
 
-        let mut nodes:Vec<u64> = vec!();
+        // a node is 8 bytes plus one byte for its type, check the whole table fits before allocating for it
+        let table_size = nodes_size.checked_mul(9).ok_or(BlobError::LengthOverflow { offset: bytes_read - 8 })?;
+        FuseStructure::get_slice(data, bytes_read, table_size)?;
+
+        let mut nodes:Vec<u64> = Vec::with_capacity(nodes_size);
 
         for _ in 0..nodes_size {
-            let node = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+            let node = FuseStructure::read_u64(data, bytes_read)?;
             nodes.push(node);
             bytes_read += 8;
         }
 
-        let mut node_types:Vec<u8> = vec!();
+        let mut node_types:Vec<u8> = Vec::with_capacity(nodes_size);
 
         for _ in 0..nodes_size {
-            node_types.push(*&FuseStructure::get_sclice_from_vector(data, bytes_read, 1)[0]);
+            node_types.push(FuseStructure::read_u8(data, bytes_read)?);
             bytes_read += 1;
         }
 
-        let parent_node = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
+        let parent_node = FuseStructure::read_u64(data, bytes_read)?;
         bytes_read += 8;
 
-        let root = *&FuseStructure::get_sclice_from_vector(data, bytes_read, 1)[0];
+        let is_root = FuseStructure::read_u8(data, bytes_read)? == 1;
         bytes_read += 1;
 
-        let is_root;
-        if root == 1 {
-            is_root = true;
-        } else {
-            is_root = false;
-        }
-
-        (FuseDirectory {
+        Ok((FuseDirectory {
             name,
             node,
             parent_node,
             nodes,
             node_types,
-            is_root
-        }, (bytes_read - start) as u64)
+            is_root,
+            names: vec!(),
+        }, (bytes_read - start) as u64))
     }
 }
 
-impl FuseCommon<FileAttr> for FileAttr {
-    fn find_by_node(container:&Vec<FileAttr>, node:u64) -> Option<&FileAttr> {
-        for attribute in container {
-            if attribute.ino == node {
-                return Some(attribute);
-            }
-        }
+impl FuseCommon<FuseSymlink> for FuseSymlink {
+    fn deserialize(start:usize, data: &[u8]) -> Result<(FuseSymlink, u64), BlobError> {
+        let mut bytes_read:usize = start;
 
-        return None;
-    }
+        let name_size = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        let name = FuseStructure::read_name(data, bytes_read, name_size)?;
+        bytes_read += name_size;
 
-    fn serialize(&self) -> Vec<u8> {
-        let mut returned:Vec<u8> = vec!();
+        let node = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
 
-        returned.extend(self.ino.to_be_bytes().to_vec());
+        let target_size = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        let target = FuseStructure::read_name(data, bytes_read, target_size)?;
+        bytes_read += target_size;
 
-        returned.extend(self.size.to_be_bytes().to_vec());
+        Ok((FuseSymlink {
+            name,
+            node,
+            target
+        }, (bytes_read - start) as u64))
+    }
+}
 
-        returned.extend(self.atime.sec.to_be_bytes().to_vec());
-        returned.extend(self.atime.nsec.to_be_bytes().to_vec());
+impl FuseCommon<FuseSpecial> for FuseSpecial {
+    fn deserialize(start:usize, data: &[u8]) -> Result<(FuseSpecial, u64), BlobError> {
+        let mut bytes_read:usize = start;
 
-        returned.extend(self.mtime.sec.to_be_bytes().to_vec());
-        returned.extend(self.mtime.nsec.to_be_bytes().to_vec());
+        let name_size = FuseStructure::read_length(data, bytes_read)?;
+        bytes_read += 8;
+        let name = FuseStructure::read_name(data, bytes_read, name_size)?;
+        bytes_read += name_size;
 
-        returned.extend(self.ctime.sec.to_be_bytes().to_vec());
-        returned.extend(self.ctime.nsec.to_be_bytes().to_vec());
+        let node = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
 
-        returned.extend(self.perm.to_be_bytes().to_vec());
+        let kind = FuseStructure::kind_from_u8(FuseStructure::read_u8(data, bytes_read)?, bytes_read)?;
+        bytes_read += 1;
 
-        if self.kind == FileType::RegularFile {
-            returned.push(1 as u8);
-        } else {
-            returned.push(0 as u8);
+        Ok((FuseSpecial {
+            name,
+            node,
+            kind
+        }, (bytes_read - start) as u64))
+    }
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest {
+            name: String::new(),
+            version: String::new(),
+            entrypoint: DEFAULT_ENTRYPOINT.to_owned(),
+            interpreter: String::new(),
+            args: vec!(),
+            workdir: String::new(),
+            env: vec!(),
+            labels: vec!(),
         }
+    }
 
+    pub fn from_entries(entries: Vec<(String, String)>) -> Manifest {
+        let mut returned = Manifest::new();
+        for (key, value) in entries {
+            match key.as_str() {
+                "name" => returned.name = value,
+                "version" => returned.version = value,
+                "entrypoint" => returned.entrypoint = value,
+                "interpreter" => returned.interpreter = value,
+                "arg" => returned.args.push(value),
+                "workdir" => returned.workdir = value,
+                _ => {
+                    if let Some(name) = key.strip_prefix("env.") {
+                        returned.env.push((name.to_owned(), value));
+                    } else if let Some(label) = key.strip_prefix("label.") {
+                        returned.labels.push((label.to_owned(), value));
+                    }
+                }
+            }
+        }
         returned
     }
 
-    fn deserialize(start:usize, data: &Vec<u8>) -> (FileAttr, u64) {
+    pub fn deserialize(start: usize, data: &[u8]) -> Result<(Manifest, u64), BlobError> {
+        let mut bytes_read: usize = start;
+
+        let number_entries = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
+
+        let mut entries: Vec<(String, String)> = vec!();
+        for _ in 0..number_entries {
+            let key_size = FuseStructure::read_length(data, bytes_read)?;
+            bytes_read += 8;
+            let key = FuseStructure::read_name(data, bytes_read, key_size)?;
+            bytes_read += key_size;
+            let value_size = FuseStructure::read_length(data, bytes_read)?;
+            bytes_read += 8;
+            let value = FuseStructure::read_name(data, bytes_read, value_size)?;
+            bytes_read += value_size;
+            entries.push((key, value));
+        }
+
+        Ok((Manifest::from_entries(entries), (bytes_read - start) as u64))
+    }
+}
+
+impl FuseCommon<FileAttr> for FileAttr {
+    fn deserialize(start:usize, data: &[u8]) -> Result<(FileAttr, u64), BlobError> {
         let mut bytes_read:usize = start;
 
-        let ino = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
-        bytes_read = bytes_read + 8;
+        let ino = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
 
-        let size = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8));
-        bytes_read = bytes_read + 8;
+        let size = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
 
-        let atime = Timespec::new(BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8)) as i64, BigEndian::read_u32(&FuseStructure::get_sclice_from_vector(data, bytes_read+8, 4)) as i32);
+        let atime = FuseStructure::read_timespec(data, bytes_read)?;
         bytes_read += 12;
-        let mtime = Timespec::new(BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8)) as i64, BigEndian::read_u32(&FuseStructure::get_sclice_from_vector(data, bytes_read+8, 4)) as i32);
+        let mtime = FuseStructure::read_timespec(data, bytes_read)?;
         bytes_read += 12;
-        let ctime = Timespec::new(BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, bytes_read, 8)) as i64, BigEndian::read_u32(&FuseStructure::get_sclice_from_vector(data, bytes_read+8, 4)) as i32);
+        let ctime = FuseStructure::read_timespec(data, bytes_read)?;
         bytes_read += 12;
 
-        let perms = BigEndian::read_u16(&FuseStructure::get_sclice_from_vector(data, bytes_read, 2));
-        bytes_read = bytes_read + 2;
-
-        let isfile = FuseStructure::get_sclice_from_vector(data, bytes_read, 1)[0];
-        bytes_read = bytes_read + 1;
+        let perms = FuseStructure::read_u16(data, bytes_read)?;
+        bytes_read += 2;
 
-        let kind;
-        if isfile == 1 {
-            kind = FileType::RegularFile;
-        } else {
-            kind = FileType::Directory;
-        }
+        let kind = FuseStructure::kind_from_u8(FuseStructure::read_u8(data, bytes_read)?, bytes_read)?;
+        bytes_read += 1;
 
-        (FileAttr {
+        Ok((FileAttr {
             ino,
             size,
             blocks: 0,
@@ -285,223 +687,905 @@ impl FuseCommon<FileAttr> for FileAttr {
             kind,
             perm: perms,
             nlink: 1,
-            uid: 501,
-            gid: 20,
+            uid: LEGACY_UID,
+            gid: LEGACY_GID,
             rdev: 0,
             flags: 0
-        }, (bytes_read - start) as u64)
+        }, (bytes_read - start) as u64))
+    }
+}
+
+impl BlobHeader {
+    pub fn deserialize(data: &[u8]) -> Result<BlobHeader, BlobError> {
+        let mut counter: usize = 0;
+
+        if FuseStructure::get_slice(data, counter, 6)? != BLOB_MAGIC {
+            return Err(BlobError::BadMagic);
+        }
+        counter += 6;
+
+        let version = FuseStructure::read_u16(data, counter)?;
+        counter += 2;
+        let flags = FuseStructure::read_u32(data, counter)?;
+        counter += 4;
+        let features = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        let number_sections = FuseStructure::read_u32(data, counter)?;
+        counter += 4;
+
+        let mut sections: Vec<BlobSection> = vec!();
+        for _ in 0..number_sections {
+            let kind = FuseStructure::read_u32(data, counter)?;
+            let offset = FuseStructure::read_u64(data, counter + 4)?;
+            let length = FuseStructure::read_u64(data, counter + 12)?;
+            counter += SECTION_ENTRY_SIZE;
+
+            sections.push(BlobSection {
+                kind,
+                offset,
+                length,
+            });
+        }
+
+        Ok(BlobHeader {
+            version,
+            flags,
+            features,
+            sections,
+        })
+    }
+
+    pub fn find_section(&self, kind: u32) -> Result<&BlobSection, BlobError> {
+        self.sections.iter().find(|section| section.kind == kind).ok_or(BlobError::MissingSection(kind))
     }
 }
 
 impl FuseStructure {
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut returned: Vec<u8> = vec!();
+    // only the tables are decoded, file contents stay in the blob until they are read
+    pub fn deserialize(blob: BlobData) -> Result<FuseStructure, BlobError> {
+        let (mut returned, features) = {
+            let data = blob.as_slice();
+            if FuseStructure::get_slice(data, 0, 6)? == LEGACY_MAGIC {
+                (FuseStructure::deserialize_rpack0(data)?, 0)
+            } else {
+                let header = BlobHeader::deserialize(data)?;
+                match header.version {
+                    FORMAT_VERSION => (FuseStructure::deserialize_v1(&header, data)?, header.features),
+                    version => return Err(BlobError::UnsupportedVersion(version))
+                }
+            }
+        };
+        returned.data = blob;
+
+        returned.build_index();
+        returned.validate()?;
+        if features & FEATURE_FULL_ATTRIBUTES == 0 {
+            returned.count_links();
+        }
+        Ok(returned)
+    }
 
-        let number_directories = self.directories.len();
-        let number_files = self.files.len();
-        let number_attributes = self.attributes.len();
+    pub fn build_index(&mut self) {
+        let mut index = NodeIndex::default();
 
-        returned.extend("rpack0".as_bytes().to_vec()); //header for blobs
-        returned.extend(number_directories.to_be_bytes().to_vec());
-        returned.extend(number_files.to_be_bytes().to_vec());
-        returned.extend(number_attributes.to_be_bytes().to_vec());
+        for (position, directory) in self.directories.iter().enumerate() {
+            index.directories.insert(directory.node, position);
+            if directory.is_root {
+                index.root = directory.node;
+            }
+        }
+        for (position, file) in self.files.iter().enumerate() {
+            index.files.insert(file.node, position);
+        }
+        for (position, symlink) in self.symlinks.iter().enumerate() {
+            index.symlinks.insert(symlink.node, position);
+        }
+        for (position, special) in self.specials.iter().enumerate() {
+            index.specials.insert(special.node, position);
+        }
+        for (position, attribute) in self.attributes.iter().enumerate() {
+            index.attributes.insert(attribute.ino, position);
+        }
+        self.index = index;
 
+        let mut children: HashMap<u64, HashMap<String, u64>> = HashMap::with_capacity(self.directories.len());
         for directory in &self.directories {
-            returned.extend(directory.serialize());
+            let mut names: HashMap<String, u64> = HashMap::with_capacity(directory.nodes.len());
+            for (i, node) in directory.nodes.iter().enumerate() {
+                if let Some((name, _)) = self.find_child_entry(directory, i) {
+                    names.insert(name.to_owned(), *node);
+                }
+            }
+            children.insert(directory.node, names);
         }
+        self.index.children = children;
+    }
+
+    pub fn read_attribute_rest(data: &[u8], start: usize, attribute: &mut FileAttr) -> Result<usize, BlobError> {
+        let mut bytes_read = start;
 
-        for file in &self.files {
-            returned.extend(file.serialize());
+        attribute.blocks = FuseStructure::read_u64(data, bytes_read)?;
+        bytes_read += 8;
+        attribute.crtime = FuseStructure::read_timespec(data, bytes_read)?;
+        bytes_read += 12;
+        attribute.nlink = FuseStructure::read_u32(data, bytes_read)?;
+        bytes_read += 4;
+        attribute.uid = FuseStructure::read_u32(data, bytes_read)?;
+        bytes_read += 4;
+        attribute.gid = FuseStructure::read_u32(data, bytes_read)?;
+        bytes_read += 4;
+        attribute.rdev = FuseStructure::read_u32(data, bytes_read)?;
+        bytes_read += 4;
+        attribute.flags = FuseStructure::read_u32(data, bytes_read)?;
+        bytes_read += 4;
+
+        Ok(bytes_read - start)
+    }
+
+    // applied once before mounting, the recorded ids are kept for Preserve
+    pub fn apply_ownership(&mut self, ownership: Ownership) {
+        let (uid, gid) = match ownership {
+            Ownership::Preserve => return,
+            Ownership::User => unsafe { (libc::getuid(), libc::getgid()) },
+            Ownership::Fixed(uid, gid) => (uid, gid)
+        };
+
+        for attribute in self.attributes.iter_mut() {
+            attribute.uid = uid;
+            attribute.gid = gid;
         }
+    }
 
-        for attribute in &self.attributes {
-            returned.extend(attribute.serialize());
+    // a file is linked once for every directory entry pointing at it
+    pub fn count_links(&mut self) {
+        let mut links: HashMap<u64, u32> = HashMap::new();
+        for directory in &self.directories {
+            for (node, node_type) in directory.nodes.iter().zip(directory.node_types.iter()) {
+                if *node_type == NODE_FILE {
+                    *links.entry(*node).or_insert(0) += 1;
+                }
+            }
         }
 
-        returned
+        for (node, count) in links {
+            if let Some(position) = self.index.attributes.get(&node) {
+                self.attributes[*position].nlink = count;
+            }
+        }
     }
 
+    // the kernel always calls the root directory 1
+    pub fn resolve_node(&self, ino: u64) -> u64 {
+        if ino == 1 {
+            self.index.root
+        } else {
+            ino
+        }
+    }
 
+    pub fn find_directory(&self, ino: u64) -> Option<&FuseDirectory> {
+        self.index.directories.get(&self.resolve_node(ino)).map(|position| &self.directories[*position])
+    }
 
-    pub fn deserialize(data:&mut Vec<u8>) -> Option<FuseStructure> {
-        let mut returned =  FuseStructure {
-            epoch: Timespec::new(0,0),
-            directories: vec!(),
-            files: vec!(),
-            attributes: vec!()
-        };
+    pub fn find_file(&self, ino: u64) -> Option<&FuseFile> {
+        self.index.files.get(&ino).map(|position| &self.files[*position])
+    }
 
-        let mut counter:usize = 0;
+    pub fn find_symlink(&self, ino: u64) -> Option<&FuseSymlink> {
+        self.index.symlinks.get(&ino).map(|position| &self.symlinks[*position])
+    }
 
-        let header = FuseStructure::get_sclice_from_vector(data, 0, 6);
-        counter += 6;
+    pub fn find_special(&self, ino: u64) -> Option<&FuseSpecial> {
+        self.index.specials.get(&ino).map(|position| &self.specials[*position])
+    }
 
-        if header == "rpack0".as_bytes().to_vec() {
-            let number_directories = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, counter, 8));
-            counter += 8;
-            let number_files = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, counter, 8));
+    pub fn find_attribute(&self, ino: u64) -> Option<&FileAttr> {
+        self.index.attributes.get(&self.resolve_node(ino)).map(|position| &self.attributes[*position])
+    }
+
+    pub fn find_child(&self, parent: u64, name: &str) -> Option<u64> {
+        self.index.children.get(&self.resolve_node(parent))?.get(name).copied()
+    }
+
+    // a slash separated path from the root, symlinks on the way are not followed
+    pub fn find_path(&self, path: &str) -> Option<u64> {
+        let mut node = self.index.root;
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            node = self.find_child(node, name)?;
+        }
+        Some(node)
+    }
+
+    // like find_path but symlinks are followed, as long as they stay inside the package
+    pub fn resolve_path(&self, path: &str) -> Option<u64> {
+        let mut pending: Vec<String> = path.split('/').rev().map(|name| name.to_owned()).collect();
+        let mut parents: Vec<u64> = vec!();
+        let mut node = self.index.root;
+        let mut hops = 0;
+        while let Some(name) = pending.pop() {
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            if name == ".." {
+                node = parents.pop()?;
+                continue;
+            }
+            let child = self.find_child(node, &name)?;
+            match self.find_symlink(child) {
+                Some(symlink) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS || symlink.target.starts_with('/') {
+                        return None;
+                    }
+                    pending.extend(symlink.target.split('/').rev().map(|name| name.to_owned()));
+                }
+                None => {
+                    parents.push(node);
+                    node = child;
+                }
+            }
+        }
+        Some(node)
+    }
+
+    // a directory's entries as (name, node, kind)
+    pub fn children(&self, node: u64) -> io::Result<Vec<(&str, u64, FileType)>> {
+        let directory = self.find_directory(node).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut returned: Vec<(&str, u64, FileType)> = vec!();
+        for i in 0..directory.nodes.len() {
+            match self.find_child_entry(directory, i) {
+                Some((name, kind)) => returned.push((name, directory.nodes[i], kind)),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("entry {} of directory {} is missing", i, node)))
+            }
+        }
+        Ok(returned)
+    }
+
+    // name and kind of the i-th child of a directory, as it is named in that directory
+    pub fn find_child_entry<'a>(&'a self, directory: &'a FuseDirectory, i: usize) -> Option<(&'a str, FileType)> {
+        let (name, kind) = self.find_entry(*directory.nodes.get(i)?, *directory.node_types.get(i)?)?;
+        match directory.names.get(i) {
+            Some(own_name) => Some((own_name.as_str(), kind)),
+            None => Some((name, kind))
+        }
+    }
+
+    // name and kind of a directory entry
+    pub fn find_entry(&self, node: u64, node_type: u8) -> Option<(&str, FileType)> {
+        match node_type {
+            NODE_FILE => self.find_file(node).map(|file| (file.name.as_str(), FileType::RegularFile)),
+            NODE_SYMLINK => self.find_symlink(node).map(|symlink| (symlink.name.as_str(), FileType::Symlink)),
+            NODE_SPECIAL => self.find_special(node).map(|special| (special.name.as_str(), special.kind)),
+            _ => self.find_directory(node).map(|directory| (directory.name.as_str(), FileType::Directory))
+        }
+    }
+
+    fn deserialize_v1(header: &BlobHeader, data: &[u8]) -> Result<FuseStructure, BlobError> {
+        let unsupported = header.features & !SUPPORTED_FEATURES;
+        if unsupported != 0 {
+            return Err(BlobError::UnsupportedFeatures(unsupported));
+        }
+        let unsupported = header.flags & !SUPPORTED_FLAGS;
+        if unsupported != 0 {
+            return Err(BlobError::UnsupportedFlags(unsupported));
+        }
+
+        let mut returned = FuseStructure::new();
+
+        let file_data = header.find_section(SECTION_DATA)?;
+        FuseStructure::get_section(data, file_data)?;
+
+        // records are read against the whole blob so errors report absolute offsets
+        let directories = header.find_section(SECTION_DIRECTORIES)?;
+        let mut counter = FuseStructure::get_section_start(data, directories)?;
+        let number_directories = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        for _ in 0..number_directories {
+            let (dir, count) = FuseDirectory::deserialize(counter, data)?;
+            returned.directories.push(dir);
+            counter += count as usize;
+        }
+        FuseStructure::check_section_end(directories, counter)?;
+
+        let files = header.find_section(SECTION_FILES)?;
+        let mut counter = FuseStructure::get_section_start(data, files)?;
+        let number_files = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        for _ in 0..number_files {
+            let (file, count) = FuseFile::deserialize_entry(counter, data, file_data, header.features)?;
+            returned.files.push(file);
+            counter += count as usize;
+        }
+        FuseStructure::check_section_end(files, counter)?;
+
+        let attributes = header.find_section(SECTION_ATTRIBUTES)?;
+        let mut counter = FuseStructure::get_section_start(data, attributes)?;
+        let number_attributes = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        for _ in 0..number_attributes {
+            let (mut attr, count) = FileAttr::deserialize(counter, data)?;
+            counter += count as usize;
+            if header.features & FEATURE_FULL_ATTRIBUTES != 0 {
+                counter += FuseStructure::read_attribute_rest(data, counter, &mut attr)?;
+            }
+            returned.attributes.push(attr);
+        }
+        FuseStructure::check_section_end(attributes, counter)?;
+
+        if let Ok(names) = header.find_section(SECTION_NAMES) {
+            let mut counter = FuseStructure::get_section_start(data, names)?;
+            let number_directories = FuseStructure::read_u64(data, counter)?;
             counter += 8;
-            let number_attributes = BigEndian::read_u64(&FuseStructure::get_sclice_from_vector(data, counter, 8));
+            let positions: HashMap<u64, usize> = returned.directories.iter().enumerate().map(|(position, directory)| (directory.node, position)).collect();
+            for _ in 0..number_directories {
+                let node = FuseStructure::read_u64(data, counter)?;
+                let position = *positions.get(&node).ok_or(BlobError::DanglingNode { node })?;
+                counter += 8;
+                let number_names = FuseStructure::read_length(data, counter)?;
+                if number_names != returned.directories[position].nodes.len() {
+                    return Err(BlobError::LengthOverflow { offset: counter });
+                }
+                counter += 8;
+
+                let mut directory_names: Vec<String> = Vec::with_capacity(number_names);
+                for _ in 0..number_names {
+                    let name_size = FuseStructure::read_length(data, counter)?;
+                    counter += 8;
+                    directory_names.push(FuseStructure::read_name(data, counter, name_size)?);
+                    counter += name_size;
+                }
+                returned.directories[position].names = directory_names;
+            }
+            FuseStructure::check_section_end(names, counter)?;
+        }
+
+        if let Ok(xattrs) = header.find_section(SECTION_XATTRS) {
+            let mut counter = FuseStructure::get_section_start(data, xattrs)?;
+            let number_nodes = FuseStructure::read_u64(data, counter)?;
             counter += 8;
+            for _ in 0..number_nodes {
+                let node = FuseStructure::read_u64(data, counter)?;
+                counter += 8;
+                let number_xattrs = FuseStructure::read_u64(data, counter)?;
+                counter += 8;
+
+                let mut node_xattrs: Vec<Xattr> = vec!();
+                for _ in 0..number_xattrs {
+                    let name_size = FuseStructure::read_length(data, counter)?;
+                    counter += 8;
+                    let name = FuseStructure::read_name(data, counter, name_size)?;
+                    counter += name_size;
+                    let value_size = FuseStructure::read_length(data, counter)?;
+                    counter += 8;
+                    let value = FuseStructure::get_slice(data, counter, value_size)?.to_vec();
+                    counter += value_size;
+                    node_xattrs.push((name, value));
+                }
+                returned.xattrs.insert(node, node_xattrs);
+            }
+            FuseStructure::check_section_end(xattrs, counter)?;
+        }
 
-            for _ in 0..number_directories {
-                let (dir, count) = FuseDirectory::deserialize(counter, data);
-                returned.directories.push(dir);
+        if let Ok(manifest) = header.find_section(SECTION_MANIFEST) {
+            let counter = FuseStructure::get_section_start(data, manifest)?;
+            let (read_manifest, count) = Manifest::deserialize(counter, data)?;
+            FuseStructure::check_section_end(manifest, counter + count as usize)?;
+            returned.manifest = read_manifest;
+        }
+
+        if let Ok(specials) = header.find_section(SECTION_SPECIAL_FILES) {
+            let mut counter = FuseStructure::get_section_start(data, specials)?;
+            let number_specials = FuseStructure::read_u64(data, counter)?;
+            counter += 8;
+            for _ in 0..number_specials {
+                let (special, count) = FuseSpecial::deserialize(counter, data)?;
+                returned.specials.push(special);
                 counter += count as usize;
             }
+            FuseStructure::check_section_end(specials, counter)?;
+        }
 
-            for _ in 0..number_files {
-                let (file, count) = FuseFile::deserialize(counter, data);
-                returned.files.push(file);
+        if let Ok(symlinks) = header.find_section(SECTION_SYMLINKS) {
+            let mut counter = FuseStructure::get_section_start(data, symlinks)?;
+            let number_symlinks = FuseStructure::read_u64(data, counter)?;
+            counter += 8;
+            for _ in 0..number_symlinks {
+                let (symlink, count) = FuseSymlink::deserialize(counter, data)?;
+                returned.symlinks.push(symlink);
                 counter += count as usize;
             }
+            FuseStructure::check_section_end(symlinks, counter)?;
+        }
 
-            for _ in 0..number_attributes {
-                let (attr, count) = FileAttr::deserialize(counter, data);
-                returned.attributes.push(attr);
-                counter += count as usize;
+        Ok(returned)
+    }
+
+    // blobs written before the versioned header, kept readable for old packages
+    fn deserialize_rpack0(data:&[u8]) -> Result<FuseStructure, BlobError> {
+        let mut returned = FuseStructure::new();
+
+        let mut counter:usize = 6;
+
+        let number_directories = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        let number_files = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+        let number_attributes = FuseStructure::read_u64(data, counter)?;
+        counter += 8;
+
+        for _ in 0..number_directories {
+            let (dir, count) = FuseDirectory::deserialize(counter, data)?;
+            returned.directories.push(dir);
+            counter += count as usize;
+        }
+
+        for _ in 0..number_files {
+            let (file, count) = FuseFile::deserialize_rpack0(counter, data)?;
+            returned.files.push(file);
+            counter += count as usize;
+        }
+
+        for _ in 0..number_attributes {
+            let (attr, count) = FileAttr::deserialize(counter, data)?;
+            returned.attributes.push(attr);
+            counter += count as usize;
+        }
+
+        // a root directory without attributes gets what was made up for ino 1 back then
+        if let Some(root) = FuseDirectory::find_root_directory(&returned.directories).map(|directory| directory.node) {
+            if !returned.attributes.iter().any(|attribute| attribute.ino == root) {
+                let timespec = Timespec::new(0, 0);
+                returned.attributes.push(FileAttr {
+                    ino: root,
+                    size: 0,
+                    blocks: 0,
+                    atime: timespec,
+                    mtime: timespec,
+                    ctime: timespec,
+                    crtime: timespec,
+                    kind: FileType::Directory,
+                    perm: 0o755,
+                    nlink: 2,
+                    uid: LEGACY_UID,
+                    gid: LEGACY_GID,
+                    rdev: 0,
+                    flags: 0,
+                });
             }
+        }
 
-        } else {
-            return None;
+        Ok(returned)
+    }
+
+    // every node a directory points at has to exist with attributes, otherwise the filesystem would fail at runtime
+    fn validate(&self) -> Result<(), BlobError> {
+        let root = FuseDirectory::find_root_directory(&self.directories).ok_or(BlobError::MissingRoot)?;
+        if self.find_attribute(root.node).is_none() {
+            return Err(BlobError::DanglingNode { node: root.node });
         }
 
-        return Some(returned);
+        for directory in &self.directories {
+            if directory.nodes.len() != directory.node_types.len() || (!directory.names.is_empty() && directory.names.len() != directory.nodes.len()) {
+                return Err(BlobError::DanglingNode { node: directory.node });
+            }
+
+            for (node, node_type) in directory.nodes.iter().zip(directory.node_types.iter()) {
+                if self.find_entry(*node, *node_type).is_none() || self.find_attribute(*node).is_none() {
+                    return Err(BlobError::DanglingNode { node: *node });
+                }
+            }
+        }
+
+        for node in self.xattrs.keys() {
+            if self.find_attribute(*node).is_none() {
+                return Err(BlobError::DanglingNode { node: *node });
+            }
+        }
+
+        Ok(())
     }
 
     pub fn new() -> FuseStructure {
-        let timespec = Timespec::new(0, 0);
-        return FuseStructure {
-            epoch: timespec,
+        FuseStructure {
+            epoch: Timespec::new(0, 0),
             directories: vec!(),
             files: vec!(),
-            attributes: vec!(FileAttr {
-                ino: 1,
-                size: 0,
-                blocks: 0,
-                atime: timespec,                                  // 1970-01-01 00:00:00
-                mtime: timespec,
-                ctime: timespec,
-                crtime: timespec,
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 2,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
+            symlinks: vec!(),
+            specials: vec!(),
+            attributes: vec!(),
+            xattrs: HashMap::new(),
+            manifest: Manifest::new(),
+            data: BlobData::Owned(vec!()),
+            cache: BlockCache::new(BLOCK_CACHE_SIZE),
+            index: NodeIndex::default(),
+            on_init: None,
+            on_unmount: None,
+        }
+    }
+
+    // a size of 0 asks how big the value is, a value that doesn't fit in size is ERANGE
+    pub fn reply_xattr<R: XattrReply>(value: &[u8], size: u32, reply: R) {
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if value.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
+    pub fn get_xattr<R: XattrReply>(&self, ino: u64, name: &str, size: u32, reply: R) {
+        if self.find_attribute(ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+
+        let value = self.xattrs.get(&ino).and_then(|xattrs| xattrs.iter().find(|(xattr, _)| xattr == name));
+        match value {
+            Some((_, value)) => FuseStructure::reply_xattr(value, size, reply),
+            None => reply.error(ENODATA)
+        }
+    }
+
+    // every name followed by a nul byte
+    pub fn list_xattrs<R: XattrReply>(&self, ino: u64, size: u32, reply: R) {
+        if self.find_attribute(ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+
+        let mut names: Vec<u8> = vec!();
+        for (name, _) in self.xattrs.get(&ino).into_iter().flatten() {
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        FuseStructure::reply_xattr(&names, size, reply);
+    }
+
+    // replies with at most size bytes starting at offset, reads at or past the end of the file get an empty reply
+    pub fn read_file<R: DataReply>(&mut self, ino: u64, offset: i64, size: u32, reply: R) {
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let file = match self.index.files.get(&ino) {
+            Some(position) => &self.files[*position],
+            None => {
+                reply.error(ENOENT);
+                return;
             }
-            ),
         };
+
+        match file.read_range(self.data.as_slice(), offset as u64, size as u64, &mut self.cache) {
+            Ok(data) => reply.data(&data),
+            Err(_) => reply.error(EIO)
+        }
     }
 
-    pub fn get_sclice_from_vector(data: &Vec<u8>, start:usize, amount:usize) -> Vec<u8> {
-        data.as_slice()[start..start + amount].to_vec()
+    pub fn get_slice(data: &[u8], start:usize, amount:usize) -> Result<&[u8], BlobError> {
+        let end = start.checked_add(amount).ok_or(BlobError::LengthOverflow { offset: start })?;
+        if end > data.len() {
+            return Err(BlobError::Truncated { offset: start, needed: amount });
+        }
+        Ok(&data[start..end])
     }
 
-}
+    pub fn get_section<'a>(data: &'a [u8], section: &BlobSection) -> Result<&'a [u8], BlobError> {
+        // sections are only described by the header, so an out of range one is reported at the header
+        let offset = usize::try_from(section.offset).map_err(|_| BlobError::LengthOverflow { offset: HEADER_SIZE })?;
+        let length = usize::try_from(section.length).map_err(|_| BlobError::LengthOverflow { offset: HEADER_SIZE })?;
+        FuseStructure::get_slice(data, offset, length)
+    }
 
-impl Filesystem for FuseStructure {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let dir_node;
-        if parent == 1 {
-            dir_node = *&FuseDirectory::find_root_directory(&self.directories).unwrap().node;
-        } else {
-            dir_node = *&FuseDirectory::find_by_node(&self.directories, parent).unwrap().node;
+    // checks the section lies inside the blob and returns where it starts
+    pub fn get_section_start(data: &[u8], section: &BlobSection) -> Result<usize, BlobError> {
+        FuseStructure::get_section(data, section)?;
+        Ok(section.offset as usize)
+    }
+
+    // records that ran past the end of their section belong to whatever comes after it
+    pub fn check_section_end(section: &BlobSection, counter: usize) -> Result<(), BlobError> {
+        let start = section.offset as usize;
+        if counter - start > section.length as usize {
+            return Err(BlobError::Truncated { offset: start, needed: counter - start });
         }
+        Ok(())
+    }
 
-        let directory= &FuseDirectory::find_by_node(&self.directories, dir_node).unwrap();
+    // offset is where the kind was read from, for the error
+    pub fn kind_from_u8(kind: u8, offset: usize) -> Result<FileType, BlobError> {
+        match kind {
+            0 => Ok(FileType::Directory),
+            1 => Ok(FileType::RegularFile),
+            2 => Ok(FileType::Symlink),
+            3 => Ok(FileType::NamedPipe),
+            4 => Ok(FileType::CharDevice),
+            5 => Ok(FileType::BlockDevice),
+            6 => Ok(FileType::Socket),
+            _ => Err(BlobError::UnknownKind { offset })
+        }
+    }
 
-        let mut i=0;
-        for node in &directory.nodes {
-            let node_type = directory.node_types[i];
+    pub fn read_u8(data: &[u8], start:usize) -> Result<u8, BlobError> {
+        Ok(FuseStructure::get_slice(data, start, 1)?[0])
+    }
 
-            if node_type == 0 { //directory
-                let directory = FuseDirectory::find_by_node(&self.directories, *node).unwrap();
-                if directory.name == name.to_str().unwrap() {
-                    let attribute = FileAttr::find_by_node(&self.attributes, *node).unwrap();
-                    reply.entry(&self.epoch, &attribute, 0);
-                    return;
-                }
-            } else { //file
-                let file = FuseFile::find_by_node(&self.files, *node).unwrap();
-                if file.name == name.to_str().unwrap() {
-                    let attribute = FileAttr::find_by_node(&self.attributes, *node).unwrap();
-                    reply.entry(&self.epoch, &attribute, 0);
-                    return;
-                }
-            }
+    pub fn read_u16(data: &[u8], start:usize) -> Result<u16, BlobError> {
+        Ok(BigEndian::read_u16(FuseStructure::get_slice(data, start, 2)?))
+    }
+
+    pub fn read_u32(data: &[u8], start:usize) -> Result<u32, BlobError> {
+        Ok(BigEndian::read_u32(FuseStructure::get_slice(data, start, 4)?))
+    }
+
+    pub fn read_u64(data: &[u8], start:usize) -> Result<u64, BlobError> {
+        Ok(BigEndian::read_u64(FuseStructure::get_slice(data, start, 8)?))
+    }
 
-            i += 1;
+    // a u64 that is used as a size or offset into the blob
+    pub fn read_length(data: &[u8], start:usize) -> Result<usize, BlobError> {
+        let length = FuseStructure::read_u64(data, start)?;
+        usize::try_from(length).map_err(|_| BlobError::LengthOverflow { offset: start })
+    }
+
+    pub fn read_name(data: &[u8], start:usize, amount:usize) -> Result<String, BlobError> {
+        let bytes = FuseStructure::get_slice(data, start, amount)?;
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(name) => Ok(name),
+            Err(_) => Err(BlobError::InvalidUtf8Name { offset: start })
         }
-        reply.error(ENOENT);
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let attribute = FileAttr::find_by_node(&self.attributes, ino);
-        if attribute.is_some() {
-            reply.attr(&self.epoch, &attribute.unwrap());
+    pub fn read_timespec(data: &[u8], start:usize) -> Result<Timespec, BlobError> {
+        let sec = FuseStructure::read_u64(data, start)? as i64;
+        let nsec = FuseStructure::read_u32(data, start + 8)? as i32;
+        if !(0..1_000_000_000).contains(&nsec) {
+            return Err(BlobError::InvalidTimestamp { offset: start + 8 });
         }
-        else {
-            reply.error(ENOENT);
+        Ok(Timespec::new(sec, nsec))
+    }
+
+}
+
+impl Filesystem for FuseStructure {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        if let Some(ready) = self.on_init.take() {
+            let _ = ready.send(()); // nobody waiting for it is fine too
         }
+        Ok(())
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, _size: u32, reply: ReplyData) {
-        let file = FuseFile::find_by_node(&self.files, ino);
-        if file.is_some() {
-            reply.data(&file.unwrap().data[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let node = match name.to_str() {
+            Some(name) => self.find_child(parent, name),
+            None => None
+        };
+
+        match node.and_then(|node| self.find_attribute(node)) {
+            Some(attribute) => reply.entry(&self.epoch, attribute, 0),
+            None => reply.error(ENOENT)
         }
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        let mut real_ino = ino;
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.find_attribute(ino) {
+            Some(attribute) => reply.attr(&self.epoch, attribute),
+            None => reply.error(ENOENT)
+        }
+    }
 
-        if ino == 1 {
-            real_ino = *&FuseDirectory::find_root_directory(&self.directories).unwrap().node;//self.directories[0].node; //root directory
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        self.read_file(ino, offset, size, reply);
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.find_symlink(ino) {
+            Some(symlink) => reply.data(symlink.target.as_bytes()),
+            None => reply.error(ENOENT)
         }
+    }
 
-        let directory = FuseDirectory::find_by_node(&self.directories, real_ino);
-        if directory.is_none() {
-            reply.error(ENOENT);
-            return;
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match name.to_str() {
+            Some(name) => self.get_xattr(ino, name, size, reply),
+            None => reply.error(ENODATA)
         }
-        let directory = directory.unwrap();
+    }
 
-        if directory.is_root {
-            if offset == 0 {
-                reply.add(real_ino, 1 as i64, FileType::Directory, ".");
-            }
-            if offset < 2 {
-                reply.add(1, 2 as i64, FileType::Directory, "..");
-            }
-        } else {
-            if offset == 0 {
-                reply.add(real_ino, 1 as i64, FileType::Directory, ".");
-            }
-            if offset < 2 {
-                reply.add(directory.parent_node, 2 as i64, FileType::Directory, "..");
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.list_xattrs(ino, size, reply);
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let directory = match self.find_directory(ino) {
+            Some(directory) => directory,
+            None => {
+                reply.error(ENOENT);
+                return;
             }
-        }
+        };
 
-        for i in 0..directory.nodes.len() as usize {
-            if i < offset as usize { //shouldn't this be offset - 2 because of . and ..?
-                continue;
+        // the kernel knows the root directory as ino 1, and the root's parent is outside of the mount
+        let mounted = |node: u64| if node == self.index.root { 1 } else { node };
+        let parent = if directory.is_root { 1 } else { mounted(directory.parent_node) };
+
+        // every entry carries the offset of the one after it, so . is 1, .. is 2 and the children follow
+        let mut entries: Vec<(u64, FileType, &str)> = Vec::with_capacity(directory.nodes.len() + 2);
+        entries.push((mounted(directory.node), FileType::Directory, "."));
+        entries.push((parent, FileType::Directory, ".."));
+        for (i, node) in directory.nodes.iter().enumerate() {
+            if let Some((name, kind)) = self.find_child_entry(directory, i) {
+                entries.push((*node, kind, name));
             }
+        }
 
-            let node = directory.nodes[i];
-            let node_type = directory.node_types[i];
-            if node_type == 1 {
-                let file = FuseFile::find_by_node(&self.files, node).unwrap();
-                reply.add(node, (i + offset as usize + 1) as i64, FileType::RegularFile, &file.name);
-            } else {
-                let directory = FuseDirectory::find_by_node(&self.directories, node).unwrap();
-                reply.add(node, (i + offset as usize + 1) as i64, FileType::Directory, &directory.name);
+        for (i, (node, kind, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            if reply.add(node, (i + 1) as i64, kind, name) {
+                break; // the buffer is full, the kernel asks again from the last offset
             }
         }
         reply.ok();
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub struct FakeReply<'a>(pub &'a mut Option<Result<Vec<u8>, c_int>>);
+
+    impl<'a> DataReply for FakeReply<'a> {
+        fn data(self, data: &[u8]) {
+            *self.0 = Some(Ok(data.to_vec()));
+        }
+
+        fn error(self, err: c_int) {
+            *self.0 = Some(Err(err));
+        }
+    }
+
+    pub const ROOT_NODE: u64 = 2;
+    pub const FILE_NODE: u64 = 3;
+
+    pub fn contents() -> Vec<u8> {
+        (0..20000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    pub fn attribute(ino: u64, kind: FileType, size: u64) -> FileAttr {
+        let timespec = Timespec::new(0, 0);
+        FileAttr { ino, size, blocks: 0, atime: timespec, mtime: timespec, ctime: timespec, crtime: timespec, kind, perm: 0o755, nlink: 1, uid: LEGACY_UID, gid: LEGACY_GID, rdev: 0, flags: 0 }
+    }
+
+    // small enough for the file to span several blocks
+    pub const BLOCK_SIZE: u32 = 4 * 1024;
+
+    // a root directory holding a single file split into several compressed blocks
+    pub fn structure() -> FuseStructure {
+        let mut fuse = FuseStructure::new();
+
+        let data = contents();
+        let mut blocks: Vec<Block> = vec!();
+        let mut stored: Vec<u8> = vec!();
+        for chunk in data.chunks(BLOCK_SIZE as usize) {
+            let compressed = zstd::bulk::compress(chunk, 3).unwrap();
+            blocks.push(Block { offset: stored.len() as u64, length: compressed.len() as u64, codec: Codec::Zstd });
+            stored.extend(compressed);
+        }
+        let data_length = stored.len() as u64;
+        fuse.data = BlobData::Owned(stored);
+        fuse.files.push(FuseFile {
+            name: "file".to_owned(),
+            node: FILE_NODE,
+            data_offset: 0,
+            data_length,
+            size: data.len() as u64,
+            block_size: BLOCK_SIZE as u64,
+            blocks,
+        });
+        fuse.directories.push(FuseDirectory {
+            name: "root".to_owned(),
+            nodes: vec!(FILE_NODE),
+            node_types: vec!(1),
+            node: ROOT_NODE,
+            is_root: true,
+            parent_node: 1,
+            names: vec!(),
+        });
+        fuse.attributes.push(attribute(ROOT_NODE, FileType::Directory, 0));
+        fuse.attributes.push(attribute(FILE_NODE, FileType::RegularFile, data.len() as u64));
+        fuse.build_index();
+        fuse
+    }
+
+    pub fn read(fuse: &mut FuseStructure, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let mut result = None;
+        fuse.read_file(ino, offset, size, FakeReply(&mut result));
+        result.expect("read did not reply")
+    }
+
+    #[test]
+    fn small_read_returns_requested_bytes() {
+        let mut fuse = structure();
+        assert_eq!(read(&mut fuse, FILE_NODE, 10, 16).unwrap(), &contents()[10..26]);
+    }
+
+    #[test]
+    fn read_across_blocks_is_limited_to_size() {
+        let mut fuse = structure();
+        let offset = BLOCK_SIZE as usize - 100;
+        let data = read(&mut fuse, FILE_NODE, offset as i64, 3 * BLOCK_SIZE).unwrap();
+        assert_eq!(data, &contents()[offset..offset + 3 * BLOCK_SIZE as usize]);
+    }
+
+    #[test]
+    fn large_read_stops_at_end_of_file() {
+        let mut fuse = structure();
+        let data = read(&mut fuse, FILE_NODE, 19000, 128 * 1024).unwrap();
+        assert_eq!(data, &contents()[19000..]);
+    }
+
+    #[test]
+    fn read_at_or_after_end_of_file_is_empty() {
+        let mut fuse = structure();
+        assert!(read(&mut fuse, FILE_NODE, 20000, 4096).unwrap().is_empty());
+        assert!(read(&mut fuse, FILE_NODE, 1 << 40, 4096).unwrap().is_empty());
+    }
+
+    #[test]
+    fn negative_offset_is_invalid() {
+        let mut fuse = structure();
+        assert_eq!(read(&mut fuse, FILE_NODE, -1, 4096), Err(EINVAL));
+    }
+
+    #[test]
+    fn unknown_inode_is_not_found() {
+        let mut fuse = structure();
+        assert_eq!(read(&mut fuse, 42, 0, 4096), Err(ENOENT));
+    }
+
+    #[test]
+    fn index_resolves_children_by_name() {
+        let fuse = structure();
+        assert_eq!(fuse.find_child(1, "file"), Some(FILE_NODE));
+        assert_eq!(fuse.find_child(ROOT_NODE, "file"), Some(FILE_NODE));
+        assert_eq!(fuse.find_child(1, "missing"), None);
+        assert_eq!(fuse.find_directory(1).unwrap().node, ROOT_NODE);
+        assert_eq!(fuse.find_attribute(FILE_NODE).unwrap().size, 20000);
+    }
+
+    #[test]
+    fn unknown_kinds_are_refused() {
+        let mut data: Vec<u8> = 4u64.to_be_bytes().to_vec();
+        data.extend(b"fifo".to_vec());
+        data.extend(4u64.to_be_bytes().to_vec());
+        data.push(3);
+        assert_eq!(FuseSpecial::deserialize(0, &data).unwrap().0.kind, FileType::NamedPipe);
+        data[20] = 7;
+        assert_eq!(FuseSpecial::deserialize(0, &data).err(), Some(BlobError::UnknownKind { offset: 20 }));
+    }
+
+    // a file entry from before block compression, with its five bytes of contents at the start of the data.
+    // The codec is the byte written for it, 0 is store and 1 zstd
+    fn unblocked_entry(codec: u8, size: u64) -> Result<(FuseFile, u64), BlobError> {
+        let mut data: Vec<u8> = b"hello".to_vec();
+        data.extend(4u64.to_be_bytes().to_vec());
+        data.extend(b"file".to_vec());
+        data.extend(FILE_NODE.to_be_bytes().to_vec());
+        data.extend(0u64.to_be_bytes().to_vec());
+        data.extend(5u64.to_be_bytes().to_vec());
+        data.push(codec);
+        data.extend(size.to_be_bytes().to_vec());
+        let section = BlobSection { kind: SECTION_DATA, offset: 0, length: 5 };
+        FuseFile::deserialize_entry(5, &data, &section, FEATURE_COMPRESSION)
+    }
+
+    #[test]
+    fn unblocked_sizes_are_bounded() {
+        assert_eq!(unblocked_entry(0, 5).unwrap().0.size, 5);
+        assert_eq!(unblocked_entry(0, 6).err(), Some(BlobError::LengthOverflow { offset: 42 }));
+        assert_eq!(unblocked_entry(1, MAX_BLOCK_SIZE as u64).unwrap().0.size, MAX_BLOCK_SIZE as u64);
+        assert_eq!(unblocked_entry(1, u64::MAX).err(), Some(BlobError::LengthOverflow { offset: 42 }));
+    }
+}
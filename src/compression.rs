@@ -0,0 +1,53 @@
+use std::io::{self, Read};
+use flate2::read::DeflateDecoder;
+
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Store,
+    Zstd,
+    Lz4,
+    Deflate,
+}
+
+// a compressed chunk of a file, offset is relative to the start of the file's stored data
+#[derive(Clone, Copy, Debug)]
+pub struct Block {
+    pub offset: u64,
+    pub length: u64,
+    pub codec: Codec,
+}
+
+impl Codec {
+    pub fn from_u8(value: u8) -> Option<Codec> {
+        match value {
+            0 => Some(Codec::Store),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            3 => Some(Codec::Deflate),
+            _ => None
+        }
+    }
+}
+
+pub fn decompress(codec: Codec, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let returned = match codec {
+        Codec::Store => data.to_vec(),
+        Codec::Zstd => zstd::bulk::decompress(data, size)?,
+        Codec::Lz4 => match lz4_flex::block::decompress(data, size) {
+            Ok(returned) => returned,
+            Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error))
+        },
+        Codec::Deflate => {
+            let mut returned = Vec::with_capacity(size);
+            DeflateDecoder::new(data).take(size as u64).read_to_end(&mut returned)?;
+            returned
+        }
+    };
+
+    if returned.len() != size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed size does not match"));
+    }
+    Ok(returned)
+}
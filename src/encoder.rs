@@ -0,0 +1,99 @@
+use std::io::{self, Write};
+use flate2::Compression as DeflateLevel;
+use flate2::write::DeflateEncoder;
+use crate::compression::*;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 128 * 1024;
+pub const MIN_BLOCK_SIZE: u32 = 4 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+    pub block_size: u32,
+}
+
+impl Codec {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Codec::Store => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+            Codec::Deflate => 3,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "none" | "store" => Some(Codec::Store),
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+            "deflate" => Some(Codec::Deflate),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Store => "store",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::Deflate => "deflate",
+        }
+    }
+
+    pub fn default_level(self) -> i32 {
+        match self {
+            Codec::Zstd => 3,
+            Codec::Deflate => 6,
+            _ => 0
+        }
+    }
+}
+
+impl Compression {
+    pub fn new(codec: Codec) -> Compression {
+        Compression {
+            codec,
+            level: codec.default_level(),
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+
+    // every block is compressed on its own so reads only have to decompress the blocks they touch
+    pub fn compress_blocks(&self, data: &[u8]) -> io::Result<(Vec<Block>, Vec<u8>)> {
+        let mut blocks: Vec<Block> = vec!();
+        let mut stored: Vec<u8> = vec!();
+
+        for chunk in data.chunks(self.block_size as usize) {
+            let (codec, compressed) = self.compress(chunk)?;
+            blocks.push(Block {
+                offset: stored.len() as u64,
+                length: compressed.len() as u64,
+                codec,
+            });
+            stored.extend(compressed);
+        }
+
+        Ok((blocks, stored))
+    }
+
+    // returns the codec that was actually used, data that doesn't get smaller is stored as is
+    pub fn compress(&self, data: &[u8]) -> io::Result<(Codec, Vec<u8>)> {
+        let compressed = match self.codec {
+            Codec::Store => return Ok((Codec::Store, data.to_vec())),
+            Codec::Zstd => zstd::bulk::compress(data, self.level)?,
+            Codec::Lz4 => lz4_flex::block::compress(data),
+            Codec::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec!(), DeflateLevel::new(self.level.clamp(0, 9) as u32));
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        };
+
+        if compressed.len() >= data.len() {
+            return Ok((Codec::Store, data.to_vec()));
+        }
+        Ok((self.codec, compressed))
+    }
+}
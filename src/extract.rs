@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use fuse::{FileAttr, FileType};
+use time::Timespec;
+use crate::common::*;
+use crate::process;
+
+// an entry's name is a single path component, anything that would end up outside of its directory is refused
+pub fn safe_entry_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/') && !name.contains('\0')
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+fn timespec(time: Timespec) -> libc::timespec {
+    libc::timespec { tv_sec: time.sec as libc::time_t, tv_nsec: time.nsec as libc::c_long }
+}
+
+// symlinks get their own times, not those of their target
+fn set_times(path: &Path, attribute: &FileAttr) -> io::Result<()> {
+    let times = [timespec(attribute.atime), timespec(attribute.mtime)];
+    let path = c_path(path)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// only root can give files away, for everyone else a different owner is skipped
+fn set_owner(path: &Path, attribute: &FileAttr) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    if unsafe { libc::lchown(c_path.as_ptr(), attribute.uid, attribute.gid) } != 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::PermissionDenied {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+fn make_special(path: &Path, kind: FileType, attribute: &FileAttr) -> io::Result<()> {
+    let format = match kind {
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::BlockDevice => libc::S_IFBLK,
+        _ => libc::S_IFSOCK
+    };
+    let c_path = c_path(path)?;
+    if unsafe { libc::mknod(c_path.as_ptr(), format | attribute.perm as libc::mode_t, attribute.rdev as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// removes a tree written by extract, its directories might not be writable anymore
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return fs::remove_file(path);
+    }
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(path)? {
+        remove_tree(&entry?.path())?;
+    }
+    fs::remove_dir(path)
+}
+
+impl FuseStructure {
+    // writes the package, or only the given paths in it with everything below them, into destination, which has to exist.
+    // Devices are skipped when they can't be made, and so are owners unless owners is set and the user may change them
+    pub fn extract(&self, destination: &Path, paths: &[String], owners: bool) -> io::Result<()> {
+        let mut links: HashMap<u64, PathBuf> = HashMap::new();
+        if paths.is_empty() {
+            return self.extract_children(self.index.root, destination, owners, &mut links);
+        }
+
+        for path in paths {
+            let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty() && *name != ".").collect();
+            if names.contains(&"..") {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("refusing to extract {}, it leaves the package", path)));
+            }
+            let node = self.find_path(path)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the package", path)))?;
+
+            match names.split_last() {
+                None => self.extract_children(self.index.root, destination, owners, &mut links)?,
+                Some((name, parents)) => {
+                    let parent = parents.iter().fold(destination.to_path_buf(), |parent, name| parent.join(name));
+                    fs::create_dir_all(&parent)?;
+                    let kind = self.find_attribute(node).map(|attribute| attribute.kind).unwrap_or(FileType::RegularFile);
+                    self.extract_node(node, kind, name, &parent, owners, &mut links)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_children(&self, node: u64, path: &Path, owners: bool, links: &mut HashMap<u64, PathBuf>) -> io::Result<()> {
+        for (name, child, kind) in self.children(node)? {
+            self.extract_node(child, kind, name, path, owners, links)?;
+        }
+        Ok(())
+    }
+
+    // links remembers where every node was written first, later names for it become hard links
+    fn extract_node(&self, node: u64, kind: FileType, name: &str, parent: &Path, owners: bool, links: &mut HashMap<u64, PathBuf>) -> io::Result<()> {
+        // a signal while the runtime extracts the package to run it stops the extraction, see process::run
+        if process::pending_signal() != 0 {
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+        if !safe_entry_name(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("refusing to extract the entry {:?} in {}", name, parent.display())));
+        }
+        let path = parent.join(name);
+        let attribute = self.find_attribute(node)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} has no attributes", path.display())))?;
+
+        if kind != FileType::Directory {
+            if let Some(first) = links.get(&node) {
+                return fs::hard_link(first, &path);
+            }
+            links.insert(node, path.clone());
+        }
+
+        match kind {
+            FileType::Directory => {
+                // writable until everything inside of it is there
+                fs::DirBuilder::new().mode(0o700).create(&path)?;
+                self.extract_children(node, &path, owners, links)?;
+            }
+            FileType::RegularFile => {
+                let file = self.find_file(node)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} has no contents", path.display())))?;
+                let mut output = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+                output.write_all(&file.read_data(self.data.as_slice())?)?;
+            }
+            FileType::Symlink => {
+                let link = self.find_symlink(node)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} has no target", path.display())))?;
+                symlink(&link.target, &path)?;
+                if owners {
+                    set_owner(&path, attribute)?;
+                }
+                return set_times(&path, attribute);
+            }
+            _ => {
+                match make_special(&path, kind, attribute) {
+                    Ok(()) => {}
+                    Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                        links.remove(&node);
+                        return Ok(());
+                    }
+                    Err(error) => return Err(error)
+                }
+            }
+        }
+
+        // changing the owner clears setuid and setgid, so it comes before the mode
+        if owners {
+            set_owner(&path, attribute)?;
+        }
+        fs::set_permissions(&path, fs::Permissions::from_mode(attribute.perm as u32))?;
+        set_times(&path, attribute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use crate::mountpoint::Mountpoint;
+    use crate::compression::Codec;
+    use crate::payload::BlobData;
+
+    // a file with a second name next to it, a symlink to it and a read only directory, named as given
+    fn package(names: [&str; 4]) -> FuseStructure {
+        let mut fuse = FuseStructure::new();
+        let root = FuseDirectory { name: String::new(), nodes: vec!(2, 3, 2, 4), node_types: vec!(NODE_FILE, NODE_SYMLINK, NODE_FILE, NODE_DIRECTORY), node: 1, is_root: true, parent_node: 0, names: names.iter().map(|name| name.to_string()).collect() };
+        let locked = FuseDirectory { name: names[3].to_owned(), nodes: vec!(), node_types: vec!(), node: 4, is_root: false, parent_node: 1, names: vec!() };
+        fuse.directories = vec!(root, locked);
+        fuse.data = BlobData::Owned(b"contents".to_vec());
+        fuse.files.push(FuseFile::single_block(names[0].to_owned(), 2, 0, 8, Codec::Store, 8));
+        fuse.symlinks.push(FuseSymlink { name: names[1].to_owned(), node: 3, target: "file".to_owned() });
+        let time = Timespec::new(1_000_000, 0);
+        for (node, kind, perm) in [(1, FileType::Directory, 0o755), (2, FileType::RegularFile, 0o751), (3, FileType::Symlink, 0o777), (4, FileType::Directory, 0o555)].iter() {
+            fuse.attributes.push(FileAttr { ino: *node, size: 0, blocks: 0, atime: time, mtime: time, ctime: time, crtime: time, kind: *kind, perm: *perm, nlink: 1, uid: 0, gid: 0, rdev: 0, flags: 0 });
+        }
+        fuse.build_index();
+        fuse
+    }
+
+    #[test]
+    fn extracted_tree_matches_the_package() {
+        let fuse = package(["file", "link", "same", "locked"]);
+
+        let mut destination = Mountpoint::private("extract").unwrap();
+        destination.extract(&fuse).unwrap();
+        let file = destination.path.join("file");
+        assert_eq!(fs::read(&file).unwrap(), b"contents");
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o7777, 0o751);
+        assert_eq!(fs::metadata(&file).unwrap().mtime(), 1_000_000);
+        assert_eq!(fs::metadata(&file).unwrap().ino(), fs::metadata(destination.path.join("same")).unwrap().ino());
+        assert_eq!(fs::read_link(destination.path.join("link")).unwrap(), Path::new("file"));
+        assert_eq!(fs::metadata(destination.path.join("locked")).unwrap().mode() & 0o7777, 0o555);
+
+        let path = destination.path.clone();
+        drop(destination);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn selected_paths_and_unsafe_names() {
+        let fuse = package(["file", "link", "same", "locked"]);
+        let mut destination = Mountpoint::private("extract").unwrap();
+        fuse.extract(&destination.path, &["./file".to_owned(), "locked".to_owned()], true).unwrap();
+        destination.extracted = vec!(destination.path.join("file"), destination.path.join("locked"));
+        assert!(destination.path.join("file").is_file());
+        assert!(destination.path.join("locked").is_dir());
+        assert!(!destination.path.join("link").exists());
+
+        assert_eq!(fuse.extract(&destination.path, &["missing".to_owned()], false).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fuse.extract(&destination.path, &["locked/../../file".to_owned()], false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let outside = Mountpoint::private("extract").unwrap();
+        let inside = outside.path.join("inside");
+        fs::create_dir(&inside).unwrap();
+        for names in [["file", "link", "../escaped", "locked"], ["/escaped", "link", "same", "locked"]].iter() {
+            let fuse = package(*names);
+            assert_eq!(fuse.extract(&inside, &[], false).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert!(!outside.path.join("escaped").exists());
+            for entry in fs::read_dir(&inside).unwrap() {
+                remove_tree(&entry.unwrap().path()).unwrap();
+            }
+        }
+        fs::remove_dir(&inside).unwrap();
+    }
+}
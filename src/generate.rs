@@ -1,24 +1,133 @@
 use std::env;
 use std::io;
 use std::fs::{self, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
 
 mod common;
+mod compression;
+mod encoder;
+mod ignore;
+mod inspect;
+mod payload;
+mod writer;
 
 use crate::common::*;
+use crate::compression::*;
+use crate::encoder::*;
+use crate::ignore::*;
+use crate::payload::*;
+use crate::generator::Verbosity;
 
 pub mod generator {
     use std::io;
-    use std::fs::{self, read, read_dir};
+    use std::ffi::CString;
+    use std::ptr;
+    use std::collections::{HashMap, HashSet};
+    use std::collections::hash_map::DefaultHasher;
+    use std::fs::{self, read, read_dir, read_link, symlink_metadata};
+    use std::hash::{Hash, Hasher};
     use std::path::{Path, PathBuf};
     use std::time::*;
     use fuse::*;
+    use fuse::FileAttr;
     use time::Timespec;
-    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
     use crate::common::*;
+    use crate::encoder::*;
+    use crate::ignore::*;
+    use crate::writer::*;
     use std::borrow::Borrow;
 
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Verbosity {
+        Quiet,
+        Normal,
+        Verbose, // every packaged entry is printed, like --dry-run does
+    }
+
+    type HardLink = (String, u64); // the name in the directory and the node it links to
+
+    // state shared by the whole walk over the directory
+    pub struct BuildContext {
+        pub compression: Compression,
+        pub hard_links: HashMap<(u64, u64), u64>, // (device, inode) on disk to the node it was packaged as
+        pub contents: HashMap<u64, Vec<(u64, u64)>>, // hash of the stored bytes to their offset and length in the data
+        pub recorded: HashSet<u64>, // files whose attributes are already in the structure
+        pub xattrs: bool,
+        pub xattr_include: Vec<String>, // namespaces like user or security, all of them when empty
+        pub xattr_exclude: Vec<String>,
+        pub special_files: bool, // fifos, devices and sockets
+        pub root: PathBuf, // the directory being packaged, ignore patterns are relative to it
+        pub ignore: IgnoreRules, // from the ignore files, grows while the walk goes deeper
+        pub command_line: IgnoreRules, // --exclude and --include, these win over the ignore files
+        pub dry_run: bool, // list what would be packaged without reading any contents
+        pub verbosity: Verbosity,
+        pub reproducible: bool, // the same tree always gives the same blob
+        pub source_date_epoch: i64, // what timestamps are clamped to when reproducible
+    }
+
+    impl BuildContext {
+        pub fn new(compression: Compression) -> BuildContext {
+            BuildContext {
+                compression,
+                hard_links: HashMap::new(),
+                contents: HashMap::new(),
+                recorded: HashSet::new(),
+                xattrs: false,
+                xattr_include: vec!(),
+                xattr_exclude: vec!(),
+                special_files: true,
+                root: PathBuf::new(),
+                ignore: IgnoreRules::new(),
+                command_line: IgnoreRules::new(),
+                dry_run: false,
+                verbosity: Verbosity::Normal,
+                reproducible: false,
+                source_date_epoch: 0,
+            }
+        }
+
+        // the path from the package root with slashes, "" for the root itself
+        fn relative(&self, path: &Path) -> Option<String> {
+            let relative = result_to_option(path.strip_prefix(&self.root))?;
+            let components = relative.iter().map(|component| component.to_str()).collect::<Option<Vec<&str>>>()?;
+            Some(components.join("/"))
+        }
+
+        fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+            self.command_line.decide(relative, is_dir)
+                .or_else(|| self.ignore.decide(relative, is_dir))
+                .unwrap_or(false)
+        }
+
+        fn wants_xattr(&self, name: &str) -> bool {
+            let namespace = name.split('.').next().unwrap_or(name);
+            (self.xattr_include.is_empty() || self.xattr_include.iter().any(|include| include == namespace))
+                && !self.xattr_exclude.iter().any(|exclude| exclude == namespace)
+        }
+
+        // identical contents are only stored once and shared by every file that has them
+        fn store(&mut self, stored: &[u8], fuse: &mut FuseStructure) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            stored.hash(&mut hasher);
+            let candidates = self.contents.entry(hasher.finish()).or_default();
+            for (offset, length) in candidates.iter() {
+                let existing = &fuse.data.as_slice()[*offset as usize..(*offset + *length) as usize];
+                if existing == stored {
+                    return *offset;
+                }
+            }
+
+            let offset = fuse.push_data(stored);
+            candidates.push((offset, stored.len() as u64));
+            offset
+        }
+    }
+
     fn read_file(path: &Path) -> Option<Vec<u8>> {
         return match read(path) {
             Ok(data) =>
@@ -29,36 +138,115 @@ pub mod generator {
         };
     }
 
-    fn blob_read_file(file_path: &Path, inode: u64) -> Option<FuseFile> {
+    fn blob_read_file(file_path: &Path, inode: u64, context: &mut BuildContext, fuse: &mut FuseStructure) -> Option<FuseFile> {
         let name = file_path.file_name()?.to_str()?.to_owned();
-        let data = read_file(&file_path)?;
+        let data = if context.dry_run { vec!() } else { read_file(file_path)? };
+        let size = data.len() as u64;
+        let (blocks, data) = result_to_option(context.compression.compress_blocks(data.as_slice()))?;
         let file = FuseFile {
             name,
             node: inode,
-            data,
+            data_offset: context.store(data.as_slice(), fuse),
+            data_length: data.len() as u64,
+            size,
+            block_size: context.compression.block_size as u64,
+            blocks,
         };
 
         Some(file)
     }
 
 
-    fn blob_read_all_files(working_directory: &Vec<PathBuf>, mut inode: u64) -> Option<(Vec<FuseFile>, u64)> {
+    // the type of the path itself, symlinks are not followed
+    fn is_file_type(path: &Path, check: fn(&fs::FileType) -> bool) -> bool {
+        match symlink_metadata(path) {
+            Ok(metadata) => check(&metadata.file_type()),
+            Err(_) => false
+        }
+    }
+
+    // files already packaged under another name are returned as links to their node instead
+    fn blob_read_all_files(working_directory: &Vec<PathBuf>, mut inode: u64, context: &mut BuildContext, fuse: &mut FuseStructure) -> Option<(Vec<FuseFile>, Vec<HardLink>, u64)> {
         let mut files: Vec<FuseFile> = vec!();
+        let mut links: Vec<HardLink> = vec!();
         for sub_path in working_directory {
-            if sub_path.is_file() {
-                let file = blob_read_file(sub_path.as_path(), inode)?;
+            if is_file_type(sub_path, fs::FileType::is_file) {
+                let metadata = result_to_option(symlink_metadata(sub_path))?;
+                if metadata.nlink() > 1 {
+                    let key = (metadata.dev(), metadata.ino());
+                    if let Some(node) = context.hard_links.get(&key) {
+                        links.push((sub_path.file_name()?.to_str()?.to_owned(), *node));
+                        continue;
+                    }
+                    context.hard_links.insert(key, inode);
+                }
+
+                let file = blob_read_file(sub_path.as_path(), inode, context, fuse)?;
                 files.push(file);
 
                 inode += 1;
             }
         }
-        Some((files, inode))
+        Some((files, links, inode))
+    }
+
+    fn blob_read_all_symlinks(working_directory: &Vec<PathBuf>, mut inode: u64) -> Option<(Vec<FuseSymlink>, u64)> {
+        let mut symlinks: Vec<FuseSymlink> = vec!();
+        for path in working_directory {
+            if is_file_type(path, fs::FileType::is_symlink) {
+                let name = path.file_name()?.to_str()?.to_owned();
+                let target = result_to_option(read_link(path))?.to_str()?.to_owned();
+                symlinks.push(FuseSymlink {
+                    name,
+                    node: inode,
+                    target,
+                });
+                inode += 1;
+            }
+        }
+        Some((symlinks, inode))
+    }
+
+    fn special_file_kind(file_type: &fs::FileType) -> Option<FileType> {
+        if file_type.is_fifo() {
+            Some(FileType::NamedPipe)
+        } else if file_type.is_char_device() {
+            Some(FileType::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(FileType::BlockDevice)
+        } else if file_type.is_socket() {
+            Some(FileType::Socket)
+        } else {
+            None
+        }
+    }
+
+    fn blob_read_all_specials(working_directory: &Vec<PathBuf>, mut inode: u64, context: &BuildContext) -> Option<(Vec<FuseSpecial>, u64)> {
+        let mut specials: Vec<FuseSpecial> = vec!();
+        if !context.special_files {
+            return Some((specials, inode));
+        }
+        for path in working_directory {
+            let kind = match symlink_metadata(path) {
+                Ok(metadata) => special_file_kind(&metadata.file_type()),
+                Err(_) => None
+            };
+            if let Some(kind) = kind {
+                specials.push(FuseSpecial {
+                    name: path.file_name()?.to_str()?.to_owned(),
+                    node: inode,
+                    kind,
+                });
+                inode += 1;
+            }
+        }
+        Some((specials, inode))
     }
 
     fn blob_read_all_directories(working_directory: &Vec<PathBuf>, mut inode: u64) -> Option<(Vec<FuseDirectory>, u64)> {
         let mut directories: Vec<FuseDirectory> = vec!();
         for path in working_directory {
-            if path.is_dir() {
+            if is_file_type(path, fs::FileType::is_dir) {
                 let name = path.file_name()?.to_str()?.to_owned();
                 directories.push(FuseDirectory {
                     name: name.to_owned(),
@@ -67,6 +255,7 @@ pub mod generator {
                     nodes: vec!(),
                     node_types: vec!(),
                     is_root: false,
+                    names: vec!(),
                 });
                 inode += 1;
             }
@@ -74,11 +263,93 @@ pub mod generator {
         Some((directories, inode))
     }
 
+    // a path without a value just has no extended attributes, like on filesystems that don't support them
+    fn read_xattr_value(path: &CString, name: &CString) -> Option<Vec<u8>> {
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) };
+        if size < 0 {
+            return None;
+        }
+        let mut value: Vec<u8> = vec![0; size as usize];
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+        if size < 0 {
+            return None;
+        }
+        value.truncate(size as usize);
+        Some(value)
+    }
+
+    // symlinks get their own attributes, not the ones of their target
+    fn blob_read_xattrs(path: &Path, node: u64, context: &BuildContext, fuse: &mut FuseStructure) -> Option<()> {
+        if !context.xattrs {
+            return Some(());
+        }
+        let path = result_to_option(CString::new(path.as_os_str().as_bytes()))?;
+
+        let size = unsafe { libc::llistxattr(path.as_ptr(), ptr::null_mut(), 0) };
+        if size <= 0 {
+            return Some(());
+        }
+        let mut names: Vec<u8> = vec![0; size as usize];
+        let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+        if size <= 0 {
+            return Some(());
+        }
+        names.truncate(size as usize);
+
+        let mut xattrs: Vec<Xattr> = vec!();
+        for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+            let name = match std::str::from_utf8(name) {
+                Ok(name) if context.wants_xattr(name) => name,
+                _ => continue
+            };
+            if let Some(value) = read_xattr_value(&path, &result_to_option(CString::new(name))?) {
+                xattrs.push((name.to_owned(), value));
+            }
+        }
+        if context.reproducible {
+            xattrs.sort();
+        }
+        if !xattrs.is_empty() {
+            fuse.xattrs.insert(node, xattrs);
+        }
+        Some(())
+    }
+
+    // everything that depends on when or by whom the tree was checked out, or on the filesystem it is on
+    pub fn normalize_attributes(fuse: &mut FuseStructure, source_date_epoch: i64) {
+        let latest = Timespec::new(source_date_epoch, 0);
+        let mut subdirectories: HashMap<u64, u32> = HashMap::new();
+        for directory in &fuse.directories {
+            let count = directory.node_types.iter().filter(|node_type| **node_type == NODE_DIRECTORY).count();
+            subdirectories.insert(directory.node, count as u32);
+        }
+
+        for attribute in fuse.attributes.iter_mut() {
+            attribute.mtime = attribute.mtime.min(latest);
+            attribute.ctime = attribute.ctime.min(latest);
+            attribute.crtime = attribute.crtime.min(latest);
+            attribute.atime = attribute.mtime;
+            attribute.uid = 0;
+            attribute.gid = 0;
+            attribute.blocks = attribute.size.div_ceil(512);
+            if let Some(count) = subdirectories.get(&attribute.ino) {
+                attribute.nlink = 2 + count;
+            }
+        }
+    }
+
     fn systemtime_to_timespec(time: SystemTime) -> Timespec {
         let duration = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
         Timespec::new(duration.as_secs() as i64, duration.subsec_nanos() as i32)
     }
 
+    // ctime is when the inode last changed, crtime when it was made, which not every filesystem records
+    fn change_times(metadata: &fs::Metadata) -> (Timespec, Timespec) {
+        let ctime = Timespec::new(metadata.ctime(), metadata.ctime_nsec() as i32);
+        let crtime = metadata.created().map(systemtime_to_timespec).unwrap_or(ctime);
+        (ctime, crtime)
+    }
+
     fn result_to_option<T, E>(result: Result<T, E>) -> Option<T> {
         match result {
             Ok(T) => Some(T),
@@ -86,61 +357,90 @@ pub mod generator {
         }
     }
 
-    pub fn blob_generate_attributes(current_path: &str, directory: &FuseDirectory, mut fuse: &mut FuseStructure) -> Option<()> {
+    // everything but the size of regular files and the link count, which the caller knows better
+    fn attribute_from_metadata(ino: u64, kind: FileType, metadata: &fs::Metadata) -> io::Result<FileAttr> {
+        let (ctime, crtime) = change_times(metadata);
+
+        Ok(FileAttr {
+            ino,
+            size: match kind {
+                FileType::RegularFile | FileType::Symlink => metadata.len(),
+                _ => 0
+            },
+            blocks: metadata.blocks(),
+            atime: systemtime_to_timespec(metadata.accessed()?),
+            mtime: systemtime_to_timespec(metadata.modified()?),
+            ctime,
+            crtime,
+            kind,
+            perm: metadata.permissions().mode() as u16,
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev() as u32,
+            flags: 0,
+        })
+    }
+
+    pub fn blob_generate_attributes(current_path: &str, directory: &FuseDirectory, fuse: &mut FuseStructure, context: &mut BuildContext) -> io::Result<()> {
         let mut i = 0;
         for node in &directory.nodes {
             let node_type = directory.node_types[i];
 
             match node_type {
-                1 => {
-                    //file
-                    let name;
-                    let ino;
-                    let size;
-                    let file = FuseFile::find_by_node(&fuse.files, *node)?;
-                    name = current_path.to_owned() + file.name.clone().as_str();
-                    size = file.data.len();
-                    ino = file.node;
-
-                    let metadata = match fs::metadata(name) {
-                        Ok(data) => data,
-                        Err(_) => panic!("Error in metadata for file.")
-                    };
+                NODE_SYMLINK => {
+                    let symlink = FuseSymlink::find_by_node(&fuse.symlinks, *node).ok_or(io::ErrorKind::NotFound)?;
+                    let name = current_path.to_owned() + symlink.name.as_str();
+                    let ino = symlink.node;
 
-                    let perms = metadata.permissions().mode();
-
-                    let accessed = result_to_option(metadata.accessed())?;
-                    let modified = result_to_option(metadata.modified())?;
-                    let created = result_to_option(metadata.created())?;
-
-                    fuse.attributes.push(FileAttr {
-                        ino,
-                        size: size as u64,
-                        blocks: 0,
-                        atime: systemtime_to_timespec(accessed),
-                        mtime: systemtime_to_timespec(modified),
-                        ctime: systemtime_to_timespec(created),
-                        crtime: systemtime_to_timespec(created),
-                        kind: FileType::RegularFile,
-                        perm: perms as u16,
-                        nlink: 1,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                    });
+                    let metadata = symlink_metadata(&name)?;
+                    blob_read_xattrs(Path::new(&name), ino, context, fuse).ok_or(io::ErrorKind::InvalidInput)?;
+
+                    fuse.attributes.push(attribute_from_metadata(ino, FileType::Symlink, &metadata)?);
+                }
+                NODE_SPECIAL => {
+                    let special = FuseSpecial::find_by_node(&fuse.specials, *node).ok_or(io::ErrorKind::NotFound)?;
+                    let name = current_path.to_owned() + special.name.as_str();
+                    let kind = special.kind;
+                    let ino = special.node;
+
+                    let metadata = symlink_metadata(&name)?;
+                    blob_read_xattrs(Path::new(&name), ino, context, fuse).ok_or(io::ErrorKind::InvalidInput)?;
+
+                    fuse.attributes.push(attribute_from_metadata(ino, kind, &metadata)?);
+                }
+                NODE_FILE => {
+                    //file
+                    let file = FuseFile::find_by_node(&fuse.files, *node).ok_or(io::ErrorKind::NotFound)?;
+                    let name = current_path.to_owned() + directory.names.get(i).unwrap_or(&file.name).as_str();
+                    let size = file.size;
+                    let ino = file.node;
+
+                    // a hard link shares the attributes of the first name it was found under
+                    if !context.recorded.insert(ino) {
+                        i += 1;
+                        continue;
+                    }
+
+                    let metadata = fs::metadata(&name)?;
+                    blob_read_xattrs(Path::new(&name), ino, context, fuse).ok_or(io::ErrorKind::InvalidInput)?;
+
+                    let mut attribute = attribute_from_metadata(ino, FileType::RegularFile, &metadata)?;
+                    attribute.size = size;
+                    attribute.nlink = 1; // counted over the package once every directory is known
+                    fuse.attributes.push(attribute);
                 }
                 _ => {
                     //directory
                     let name;
                     let directory: &FuseDirectory = {
-                        let directory = FuseDirectory::find_by_node(&fuse.directories, *node)?;
+                        let directory = FuseDirectory::find_by_node(&fuse.directories, *node).ok_or(io::ErrorKind::NotFound)?;
                         name = current_path.to_owned() + directory.name.as_str() + "/";
                         &directory.clone()
                     };
 
 
-                    blob_generate_attributes(name.as_str(), directory, fuse)?;
+                    blob_generate_attributes(name.as_str(), directory, fuse, context)?;
                 }
             };
 
@@ -148,43 +448,35 @@ pub mod generator {
             i += 1;
         }
 
-        let metadata = match fs::metadata(current_path) {
-            Ok(data) => data,
-            Err(_) => panic!("Error in metadata for directory.")
-        };
+        let metadata = fs::metadata(current_path)?;
+        blob_read_xattrs(Path::new(current_path), directory.node, context, fuse).ok_or(io::ErrorKind::InvalidInput)?;
 
-        let perms = metadata.permissions().mode();
-
-        let accessed = result_to_option(metadata.accessed())?;
-        let modified = result_to_option(metadata.modified())?;
-        let created = result_to_option(metadata.created())?;
-
-        fuse.attributes.push(FileAttr {
-            ino: directory.node,
-            size: 0,
-            blocks: 0,
-            atime: systemtime_to_timespec(accessed),
-            mtime: systemtime_to_timespec(modified),
-            ctime: systemtime_to_timespec(created),
-            crtime: systemtime_to_timespec(created),
-            kind: FileType::Directory,
-            perm: perms as u16,
-            nlink: 1,
-            uid: 501,
-            gid: 20,
-            rdev: 0,
-            flags: 0,
-        });
-        Some(())
+        fuse.attributes.push(attribute_from_metadata(directory.node, FileType::Directory, &metadata)?);
+        Ok(())
+    }
+
+    // the entrypoint has to be a file and the working directory a directory, symlinks inside the package to them count too
+    pub fn check_manifest(fuse: &FuseStructure, manifest: &Manifest, directory: &str) -> io::Result<()> {
+        let kind = |path: &str| fuse.resolve_path(path).and_then(|node| fuse.find_attribute(node)).map(|attribute| attribute.kind);
+        if kind(&manifest.entrypoint) != Some(FileType::RegularFile) {
+            eprintln!("The entrypoint {} is not a file in {}.", manifest.entrypoint, directory);
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if !manifest.workdir.is_empty() && kind(&manifest.workdir) != Some(FileType::Directory) {
+            eprintln!("The working directory {} is not a directory in {}.", manifest.workdir, directory);
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        Ok(())
     }
 
-    pub fn build_blob(path: &Path, mut inode: u64, parent: u64, current_node: u64, mut fuse: &mut FuseStructure, is_root: bool) -> Option<u64> {
+    pub fn build_blob(path: &Path, mut inode: u64, parent: u64, current_node: u64, mut fuse: &mut FuseStructure, is_root: bool, context: &mut BuildContext) -> Option<u64> {
         let mut files: Vec<FuseFile> = vec!();
         let mut nodes: Vec<u64> = vec!();
         let mut node_types: Vec<u8> = vec!();
+        let mut names: Vec<String> = vec!();
 
         if path.is_dir() { //should always be true
-            let directory = match result_to_option(read_dir(path))?.map(|res| res.map(|e| e.path())).collect::<Result<Vec<_>, io::Error>>() {
+            let mut directory = match result_to_option(read_dir(path))?.map(|res| res.map(|e| e.path())).collect::<Result<Vec<_>, io::Error>>() {
                 Ok(dir) => {
                     dir
                 },
@@ -193,7 +485,35 @@ pub mod generator {
                 }
             };
 
-            let (temp_files, temp_inode) = blob_read_all_files(&directory, inode)?;
+            //read_dir has no order of its own, inodes follow the order entries are found in
+            if context.reproducible {
+                directory.sort();
+            }
+
+            //drop excluded entries before anything is read from them
+            let relative = context.relative(path)?;
+            if let Err(error) = context.ignore.read_file(&relative, &path.join(IGNORE_FILE)) {
+                println!("Could not read {} in {}: {}", IGNORE_FILE, path.display(), error);
+                return None;
+            }
+            let mut kept: Vec<PathBuf> = vec!();
+            for entry in directory {
+                let entry_relative = context.relative(&entry)?;
+                let is_dir = is_file_type(&entry, fs::FileType::is_dir);
+                if !context.is_excluded(&entry_relative, is_dir) {
+                    if context.dry_run || context.verbosity == Verbosity::Verbose {
+                        println!("{}{}", entry_relative, if is_dir { "/" } else { "" });
+                    }
+                    kept.push(entry);
+                }
+            }
+            directory = kept;
+
+            let (temp_files, temp_links, temp_inode) = blob_read_all_files(&directory, inode, context, fuse)?;
+            inode = temp_inode;
+            let (temp_symlinks, temp_inode) = blob_read_all_symlinks(&directory, inode)?;
+            inode = temp_inode;
+            let (temp_specials, temp_inode) = blob_read_all_specials(&directory, inode, context)?;
             inode = temp_inode;
             let (temp_directories, temp_inode) = blob_read_all_directories(&directory, inode)?;
             inode = temp_inode;
@@ -203,23 +523,43 @@ pub mod generator {
                 //create data for sub directory
                 let start_path_name = path.to_str()?.to_owned();
                 let name = start_path_name + temp_dir.name.as_str() + "/";//.as_str();
-                inode = build_blob(&Path::new(name.as_str()), inode, current_node, temp_dir.node, fuse, false)?;
+                inode = build_blob(&Path::new(name.as_str()), inode, current_node, temp_dir.node, fuse, false, context)?;
             }
 
             //build nodes and node_types
+            //every child is named here too, so hard links keep the name they have in this directory
             for temp_file in temp_files {
                 let node = temp_file.node;
+                names.push(temp_file.name.clone());
                 files.push(temp_file);
                 nodes.push(node);
-                node_types.push(1);
+                node_types.push(NODE_FILE);
+            }
+            for (name, node) in temp_links {
+                names.push(name);
+                nodes.push(node);
+                node_types.push(NODE_FILE);
+            }
+            for temp_symlink in &temp_symlinks {
+                names.push(temp_symlink.name.clone());
+                nodes.push(temp_symlink.node);
+                node_types.push(NODE_SYMLINK);
+            }
+            for temp_special in &temp_specials {
+                names.push(temp_special.name.clone());
+                nodes.push(temp_special.node);
+                node_types.push(NODE_SPECIAL);
             }
             for temp_dir in temp_directories {
                 let node = temp_dir.node;
+                names.push(temp_dir.name);
                 nodes.push(node);
-                node_types.push(0);
+                node_types.push(NODE_DIRECTORY);
             }
             //add them to fuse structure
             fuse.files.extend(files);
+            fuse.symlinks.extend(temp_symlinks);
+            fuse.specials.extend(temp_specials);
 
             //update directories with remaining data
             fuse.directories.push(FuseDirectory {
@@ -229,6 +569,7 @@ pub mod generator {
                 node: current_node,
                 is_root,
                 parent_node: parent,
+                names,
             });
 
             //build attributes in another function
@@ -240,34 +581,360 @@ pub mod generator {
 }
 
 
+// the runtime stub followed by the blob and the trailer that finds it again
+fn append_payload(stub: &[u8], blob: &[u8]) -> Vec<u8> {
+    let mut returned: Vec<u8> = Vec::with_capacity(stub.len() + blob.len() + TRAILER_SIZE);
+
+    returned.extend(stub);
+    returned.extend(blob);
+    returned.extend((stub.len() as u64).to_be_bytes().to_vec());
+    returned.extend((blob.len() as u64).to_be_bytes().to_vec());
+    returned.extend(TRAILER_MAGIC.to_vec());
+
+    returned
+}
+
+// a packaged binary's blob, or the whole file when it is a bare blob like generate writes without --runtime
+fn map_blob(path: &Path) -> io::Result<BlobData> {
+    let map = map_file(path)?;
+    let (offset, length) = find_payload(&map).unwrap_or((0, map.len()));
+    Ok(BlobData::Mapped(Arc::new(map), offset, length))
+}
+
+const HELP: &str = "Usage: generate [options] <directory> [<runtime> <output>]
+       generate inspect [--json] [--info] <blob or package>
+
+Packages a directory into a blob, or into a single executable when a runtime is given.
+
+Output:
+  -o, --output <path>            where to write the result, ./out.blob by default
+  --runtime <path>               append the blob to this prebuilt rpackage runtime, needs --output
+
+Package:
+  --entrypoint <path>            what the package runs, relative to the directory (startup.sh)
+  --name <name>                  the package's name, the directory's name by default
+  --version <version>            the package's version
+  --interpreter <command>        run the entrypoint with this, like bash or python3, instead of executing it
+  --arg <arg>                    an argument the entrypoint always gets, can be given more than once
+  --env <name>=<value>           an environment variable the entrypoint gets, can be given more than once
+  --workdir <path>               start the entrypoint in this directory of the package instead of the caller's
+  --label <key>=<value>          extra metadata, can be given more than once
+
+Contents:
+  --compression <codec>          none, zstd (default), lz4 or deflate
+  --level <level>                compression level
+  --block-size <KiB>             size of the blocks files are compressed in, 128 by default
+  --exclude <glob>               leave out what matches, in .rpackignore syntax
+  --include <glob>               bring back something that was excluded
+  --xattrs                       package extended attributes
+  --xattr-include <namespaces>   only these comma separated namespaces, implies --xattrs
+  --xattr-exclude <namespaces>   not these comma separated namespaces, implies --xattrs
+  --no-special-files             leave out fifos, devices and sockets
+  --reproducible                 byte identical output for identical trees, see SOURCE_DATE_EPOCH
+
+Inspect:
+  --json                         list the entries as JSON
+  --info                         print the format, the manifest and entry counts instead of the entries
+
+Other:
+  --dry-run                      print what would be packaged and stop
+  -v, --verbose                  print every entry that is packaged
+  -q, --quiet                    only print errors
+  -h, --help                     print this help";
+
+fn usage() -> io::Result<()> {
+    println!("Usage: generate [options] <directory> [<runtime> <output>] or generate inspect [--json] [--info] <blob>, see generate --help");
+    Err(io::Error::from(std::io::ErrorKind::Other))
+}
+
+// lists what a blob or a packaged binary holds, or describes it with --info
+fn inspect(arguments: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut json = false;
+    let mut info = false;
+    let mut path: Option<String> = None;
+    for arg in arguments {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--info" => info = true,
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return usage()
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return usage()
+    };
+
+    let fuse = match FuseStructure::deserialize(map_blob(Path::new(&path))?) {
+        Ok(fuse) => fuse,
+        Err(error) => {
+            println!("{} is not a valid package: {}", path, error);
+            return Err(io::Error::new(std::io::ErrorKind::InvalidData, error));
+        }
+    };
+    if info {
+        inspect::print(&fuse.info())
+    } else if json {
+        inspect::print(&fuse.list_json()?)
+    } else {
+        inspect::print(&fuse.list()?)
+    }
+}
+
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    if env::args().nth(1).as_deref() == Some("inspect") {
+        return inspect(env::args().skip(2));
+    }
 
-    if args.len() != 2 {
-        println!("Usage: generate <directory>");
-        return Err(io::Error::from(std::io::ErrorKind::Other));
+    let mut args: Vec<String> = vec!();
+    let mut compression = Compression::new(Codec::Zstd);
+    let mut level: Option<i32> = None;
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut xattrs = false;
+    let mut xattr_include: Vec<String> = vec!();
+    let mut xattr_exclude: Vec<String> = vec!();
+    let mut special_files = true;
+    let mut command_line = IgnoreRules::new();
+    let mut dry_run = false;
+    let mut reproducible = false;
+    let mut output: Option<String> = None;
+    let mut runtime: Option<String> = None;
+    let mut manifest = Manifest::new();
+    let mut verbosity = Verbosity::Normal;
+
+    let mut arguments = env::args();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            "-v" | "--verbose" => verbosity = Verbosity::Verbose,
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-o" | "--output" | "--runtime" | "--entrypoint" | "--interpreter" | "--arg" | "--workdir" | "--name" | "--version" => {
+                let value = match arguments.next() {
+                    Some(value) => value,
+                    None => return usage()
+                };
+                match arg.as_str() {
+                    "--runtime" => runtime = Some(value),
+                    "--entrypoint" => manifest.entrypoint = value,
+                    "--interpreter" => manifest.interpreter = value,
+                    "--arg" => manifest.args.push(value),
+                    "--workdir" => manifest.workdir = value,
+                    "--name" => manifest.name = value,
+                    "--version" => manifest.version = value,
+                    _ => output = Some(value)
+                }
+            }
+            "--label" | "--env" => {
+                let (key, value) = match arguments.next().as_deref().and_then(|pair| pair.split_once('=')) {
+                    Some((key, value)) if !key.is_empty() => (key.to_owned(), value.to_owned()),
+                    _ => return usage()
+                };
+                if arg == "--env" {
+                    manifest.env.push((key, value));
+                } else {
+                    manifest.labels.push((key, value));
+                }
+            }
+            "--compression" => {
+                compression = match arguments.next().as_deref().and_then(Codec::from_name) {
+                    Some(codec) => Compression::new(codec),
+                    None => return usage()
+                };
+            }
+            "--level" => {
+                level = match arguments.next().and_then(|level| level.parse().ok()) {
+                    Some(level) => Some(level),
+                    None => return usage()
+                };
+            }
+            "--block-size" => {
+                block_size = match arguments.next().and_then(|size| size.parse::<u32>().ok()).and_then(|size| size.checked_mul(1024)) {
+                    Some(size) if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size) => size,
+                    _ => return usage()
+                };
+            }
+            "--xattrs" => xattrs = true,
+            "--no-special-files" => special_files = false,
+            "--exclude" | "--include" => {
+                let glob = match arguments.next() {
+                    Some(glob) => glob,
+                    None => return usage()
+                };
+                if arg == "--include" {
+                    command_line.add("", &("!".to_owned() + glob.as_str()));
+                } else {
+                    command_line.add("", &glob);
+                }
+            }
+            "--dry-run" => dry_run = true,
+            "--reproducible" => reproducible = true,
+            "--xattr-include" | "--xattr-exclude" => {
+                let namespaces = match arguments.next() {
+                    Some(namespaces) => namespaces.split(',').map(|namespace| namespace.to_owned()).collect::<Vec<String>>(),
+                    None => return usage()
+                };
+                if arg == "--xattr-include" {
+                    xattr_include.extend(namespaces);
+                } else {
+                    xattr_exclude.extend(namespaces);
+                }
+                xattrs = true;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 && !args.is_empty() => {
+                println!("Unknown option {}", arg);
+                return usage();
+            }
+            _ => args.push(arg)
+        }
+    }
+    if let Some(level) = level {
+        compression.level = level;
+    }
+    compression.block_size = block_size;
+
+    //the older form, generate <directory> <runtime> <output>
+    if args.len() == 4 {
+        runtime = Some(args.remove(2));
+        output = Some(args.remove(2));
+    }
+    if args.len() != 2 || (runtime.is_some() && output.is_none()) {
+        return usage();
     }
-    let directory = args[1].as_str();
+    let output = output.unwrap_or_else(|| "./out.blob".to_owned());
+
+    //paths below the directory are built by appending to it
+    let directory = if args[1].ends_with('/') { args[1].clone() } else { args[1].clone() + "/" };
 
-    let first = fs::metadata(directory)?;
+    let first = fs::metadata(&directory)?;
     if first.is_dir() == false {
         println!("You have to use this program on a directory.");
         return Err(io::Error::from(std::io::ErrorKind::Other));
     }
+    if manifest.name.is_empty() {
+        if let Some(name) = Path::new(&directory).file_name().and_then(|name| name.to_str()) {
+            manifest.name = name.to_owned();
+        }
+    }
+
     let mut fuse: FuseStructure = FuseStructure::new();
-    let result = generator::build_blob(Path::new(directory), 3, 1, 2, &mut fuse, true);
+    let mut context = generator::BuildContext::new(compression);
+    context.xattrs = xattrs;
+    context.xattr_include = xattr_include;
+    context.xattr_exclude = xattr_exclude;
+    context.special_files = special_files;
+    context.root = PathBuf::from(&directory);
+    context.command_line = command_line;
+    context.dry_run = dry_run;
+    context.verbosity = verbosity;
+    context.reproducible = reproducible;
+    if reproducible {
+        //timestamps are clamped to SOURCE_DATE_EPOCH, without it everything is dated 1970
+        context.source_date_epoch = match env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => match epoch.parse() {
+                Ok(epoch) => epoch,
+                Err(_) => {
+                    println!("SOURCE_DATE_EPOCH has to be a number of seconds, not {}", epoch);
+                    return Err(io::Error::from(std::io::ErrorKind::InvalidInput));
+                }
+            },
+            Err(_) => 0
+        };
+    }
+    let result = generator::build_blob(Path::new(&directory), 3, 1, 2, &mut fuse, true, &mut context);
     if result.is_none() {
         println!("Error, aborting!");
         return Err(io::Error::from(std::io::ErrorKind::Other));
     }
-    let result = generator::blob_generate_attributes(args[1].as_str(), FuseDirectory::find_root_directory(&fuse.clone().directories).unwrap(), &mut fuse);
-    if result.is_none() {
-        println!("attribute error, aborting!");
-        return Err(io::Error::from(std::io::ErrorKind::Other));
+    if dry_run {
+        return Ok(());
+    }
+    let result = generator::blob_generate_attributes(directory.as_str(), FuseDirectory::find_root_directory(&fuse.directories.clone()).unwrap(), &mut fuse, &mut context);
+    if let Err(error) = result {
+        println!("attribute error: {}, aborting!", error);
+        return Err(error);
+    }
+    if reproducible {
+        generator::normalize_attributes(&mut fuse, context.source_date_epoch);
+    }
+    fuse.build_index();
+    fuse.count_links();
+
+    generator::check_manifest(&fuse, &manifest, &directory)?;
+    fuse.manifest = manifest;
+
+    let blob = fuse.serialize();
+    if let Some(runtime) = runtime {
+        //append the blob to a prebuilt runtime instead of writing it out on its own
+        let stub = fs::read(runtime.as_str())?;
+        let packaged = append_payload(stub.as_slice(), blob.as_slice());
+
+        let mut file = File::create(output.as_str())?;
+        file.write_all(packaged.as_slice())?;
+        file.set_permissions(fs::Permissions::from_mode(0o755))?;
+    } else {
+        let mut file = File::create(output.as_str())?;
+        file.write_all(blob.as_slice())?;
     }
 
-    let mut file = File::create("./out.blob")?;
-    file.write_all(fuse.serialize().as_slice())?;
+    if verbosity != Verbosity::Quiet {
+        println!("Packaged {} files and {} directories from {} into {}", fuse.files.len(), fuse.directories.len(), directory, output);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse::{FileAttr, FileType};
+    use time::Timespec;
+
+    #[test]
+    fn symlinked_entrypoints_are_followed() {
+        let directory = env::temp_dir().join(format!("rpackage-generate-{}", std::process::id()));
+        fs::create_dir_all(directory.join("bin")).unwrap();
+        fs::write(directory.join("bin/run.sh"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("bin/run.sh", directory.join("start")).unwrap();
+        std::os::unix::fs::symlink("./tools/../start", directory.join("again")).unwrap();
+        std::os::unix::fs::symlink("bin", directory.join("tools")).unwrap();
+        std::os::unix::fs::symlink("/bin/sh", directory.join("outside")).unwrap();
+        std::os::unix::fs::symlink("loop", directory.join("loop")).unwrap();
+
+        let path = directory.to_str().unwrap().to_owned() + "/";
+        let mut fuse = FuseStructure::new();
+        let mut context = generator::BuildContext::new(Compression::new(Codec::Store));
+        context.root = PathBuf::from(&path);
+        let built = generator::build_blob(Path::new(&path), 3, 1, 2, &mut fuse, true, &mut context);
+        let root = FuseDirectory::find_root_directory(&fuse.directories).unwrap().clone();
+        let attributes = generator::blob_generate_attributes(&path, &root, &mut fuse, &mut context);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(built.is_some());
+        attributes.unwrap();
+        fuse.build_index();
+
+        let mut manifest = Manifest::new();
+        manifest.workdir = "tools".to_owned();
+        for (entrypoint, accepted) in [("start", true), ("again", true), ("tools/run.sh", true), ("tools", false), ("outside", false), ("loop", false)].iter() {
+            manifest.entrypoint = entrypoint.to_string();
+            assert_eq!(generator::check_manifest(&fuse, &manifest, &path).is_ok(), *accepted, "{}", entrypoint);
+        }
+    }
+
+    #[test]
+    fn normalized_attributes_do_not_depend_on_the_checkout() {
+        let mut fuse = FuseStructure::new();
+        let later = Timespec::new(1_800_000_000, 5);
+        let earlier = Timespec::new(1_600_000_000, 5);
+        fuse.attributes.push(FileAttr { ino: 2, size: 513, blocks: 0, atime: later, mtime: later, ctime: earlier, crtime: earlier, kind: FileType::Directory, perm: 0o755, nlink: 2, uid: 1000, gid: 20, rdev: 0, flags: 0 });
+
+        generator::normalize_attributes(&mut fuse, 1_700_000_000);
+        let attribute = &fuse.attributes[0];
+        assert_eq!(attribute.mtime, Timespec::new(1_700_000_000, 0));
+        assert_eq!(attribute.atime, attribute.mtime);
+        assert_eq!(attribute.ctime, Timespec::new(1_600_000_000, 5));
+        assert_eq!((attribute.uid, attribute.gid, attribute.blocks), (0, 0, 2));
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// the file generate reads ignore patterns from, in the package root and any directory below it
+pub const IGNORE_FILE: &str = ".rpackignore";
+
+// one line of an ignore file or one --exclude/--include, in gitignore syntax
+#[derive(Clone, Debug)]
+pub struct IgnorePattern {
+    pub base: String, // the directory the pattern was read in, relative to the package root, "" for the root
+    pub glob: String,
+    pub negated: bool, // !pattern or --include, brings back something an earlier pattern excluded
+    pub directory_only: bool, // pattern/
+    pub anchored: bool, // a pattern with a slash in it is matched from base, otherwise against the name at any depth
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules {
+    pub patterns: Vec<IgnorePattern>, // later patterns win over earlier ones
+}
+
+impl IgnorePattern {
+    pub fn parse(base: &str, line: &str) -> Option<IgnorePattern> {
+        let mut line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let negated = line.starts_with('!');
+        if negated || line.starts_with("\\!") || line.starts_with("\\#") {
+            line = &line[1..];
+        }
+
+        let directory_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/');
+        if glob.is_empty() {
+            return None;
+        }
+
+        Some(IgnorePattern {
+            base: base.to_owned(),
+            glob: glob.to_owned(),
+            negated,
+            directory_only,
+            anchored,
+        })
+    }
+
+    // relative is the path from the package root, separated by slashes
+    pub fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+
+        let below_base = if self.base.is_empty() {
+            relative
+        } else {
+            match relative.strip_prefix(self.base.as_str()).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => return false
+            }
+        };
+
+        if self.anchored {
+            glob_matches(self.glob.as_bytes(), below_base.as_bytes())
+        } else {
+            let name = below_base.rsplit('/').next().unwrap_or(below_base);
+            glob_matches(self.glob.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+impl IgnoreRules {
+    pub fn new() -> IgnoreRules {
+        IgnoreRules::default()
+    }
+
+    pub fn add(&mut self, base: &str, line: &str) {
+        if let Some(pattern) = IgnorePattern::parse(base, line) {
+            self.patterns.push(pattern);
+        }
+    }
+
+    // a directory without an ignore file adds nothing
+    pub fn read_file(&mut self, base: &str, path: &Path) -> io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error)
+        };
+        for line in contents.lines() {
+            self.add(base, line);
+        }
+        Ok(())
+    }
+
+    // None when no pattern says anything about the path
+    pub fn decide(&self, relative: &str, is_dir: bool) -> Option<bool> {
+        let mut excluded = None;
+        for pattern in &self.patterns {
+            if pattern.matches(relative, is_dir) {
+                excluded = Some(!pattern.negated);
+            }
+        }
+        excluded
+    }
+
+    pub fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+        self.decide(relative, is_dir).unwrap_or(false)
+    }
+}
+
+// * and ? stop at slashes, ** crosses them and [...] is a character class
+pub fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    if glob.is_empty() {
+        return text.is_empty();
+    }
+
+    match glob[0] {
+        b'*' if glob.get(1) == Some(&b'*') => {
+            let rest = &glob[2..];
+            if let Some(rest) = rest.strip_prefix(b"/") {
+                // **/ is any number of leading directories, including none
+                if glob_matches(rest, text) {
+                    return true;
+                }
+                text.iter().enumerate().any(|(i, byte)| *byte == b'/' && glob_matches(rest, &text[i + 1..]))
+            } else {
+                (0..=text.len()).any(|i| glob_matches(rest, &text[i..]))
+            }
+        }
+        b'*' => {
+            let rest = &glob[1..];
+            for i in 0..=text.len() {
+                if glob_matches(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        b'?' => !text.is_empty() && text[0] != b'/' && glob_matches(&glob[1..], &text[1..]),
+        b'[' => {
+            match (text.first(), class_matches(&glob[1..], text.first().copied().unwrap_or(0))) {
+                (Some(byte), Some((matched, length))) if *byte != b'/' => matched && glob_matches(&glob[1 + length..], &text[1..]),
+                (_, None) => !text.is_empty() && text[0] == b'[' && glob_matches(&glob[1..], &text[1..]), // an unclosed [ is literal
+                _ => false
+            }
+        }
+        b'\\' if glob.len() > 1 => !text.is_empty() && text[0] == glob[1] && glob_matches(&glob[2..], &text[1..]),
+        byte => !text.is_empty() && text[0] == byte && glob_matches(&glob[1..], &text[1..])
+    }
+}
+
+// the part of a [...] class after the [, returns whether byte is in it and how long the class is including the ]
+fn class_matches(class: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        if class[i] == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            if (class[i]..=class[i + 2]).contains(&byte) {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if class[i] == byte {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        for line in lines {
+            rules.add("", line);
+        }
+        rules
+    }
+
+    #[test]
+    fn patterns_follow_gitignore_rules() {
+        let rules = rules(&["# editor junk", "*.swp", "target/", "/build", "docs/**/*.tmp", "*.log", "!keep.log", "[ab]?.txt"]);
+
+        assert!(rules.is_excluded("src/.main.rs.swp", false));
+        assert!(rules.is_excluded("target", true));
+        assert!(!rules.is_excluded("target", false));
+        assert!(rules.is_excluded("sub/target", true));
+        assert!(rules.is_excluded("build", true));
+        assert!(!rules.is_excluded("sub/build", true));
+        assert!(rules.is_excluded("docs/a/b/c.tmp", false));
+        assert!(rules.is_excluded("docs/c.tmp", false));
+        assert!(rules.is_excluded("sub/error.log", false));
+        assert!(!rules.is_excluded("sub/keep.log", false));
+        assert!(rules.is_excluded("a1.txt", false));
+        assert!(!rules.is_excluded("c1.txt", false));
+        assert!(!rules.is_excluded("startup.sh", false));
+    }
+
+    #[test]
+    fn nested_patterns_only_apply_below_their_directory() {
+        let mut rules = IgnoreRules::new();
+        rules.add("sub", "*.txt");
+        rules.add("sub", "/only/here");
+
+        assert!(rules.is_excluded("sub/a.txt", false));
+        assert!(rules.is_excluded("sub/deeper/a.txt", false));
+        assert!(!rules.is_excluded("a.txt", false));
+        assert!(!rules.is_excluded("subdir/a.txt", false));
+        assert!(rules.is_excluded("sub/only/here", false));
+        assert!(!rules.is_excluded("sub/deeper/only/here", false));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+use fuse::{FileAttr, FileType};
+use time::Timespec;
+use crate::common::*;
+
+pub const FEATURE_LABELS: [(u64, &str); 8] = [
+    (FEATURE_COMPRESSION, "compression"),
+    (FEATURE_BLOCKS, "blocks"),
+    (FEATURE_SYMLINKS, "symlinks"),
+    (FEATURE_NAMES, "names"),
+    (FEATURE_FULL_ATTRIBUTES, "full-attributes"),
+    (FEATURE_XATTRS, "xattrs"),
+    (FEATURE_SPECIAL_FILES, "special-files"),
+    (FEATURE_MANIFEST, "manifest"),
+];
+
+// one entry of the package as it is listed, a hard link is listed under every name
+pub struct ListEntry {
+    pub path: String, // from the package root, without a leading slash
+    pub kind: FileType,
+    pub attribute: FileAttr,
+    pub stored: u64, // what the contents take up in the blob, only for files
+    pub target: String, // only for symlinks
+}
+
+pub fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::Directory => "directory",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "char-device",
+        FileType::BlockDevice => "block-device",
+        FileType::Socket => "socket"
+    }
+}
+
+// drwxr-xr-x, with s and t for setuid, setgid and sticky like ls prints them
+pub fn mode_string(kind: FileType, perm: u16) -> String {
+    let mut returned = String::new();
+    returned.push(match kind {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::NamedPipe => 'p',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Socket => 's',
+        FileType::RegularFile => '-'
+    });
+
+    let specials = [(0o4000, 's', 'S'), (0o2000, 's', 'S'), (0o1000, 't', 'T')];
+    for (i, (special, with_execute, without_execute)) in specials.iter().enumerate() {
+        let bits = (perm >> (6 - 3 * i)) & 0o7;
+        returned.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        returned.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        returned.push(match (bits & 0o1 != 0, perm & special != 0) {
+            (true, true) => *with_execute,
+            (false, true) => *without_execute,
+            (true, false) => 'x',
+            (false, false) => '-'
+        });
+    }
+    returned
+}
+
+pub fn format_time(time: Timespec) -> String {
+    match time::at_utc(time).strftime("%Y-%m-%d %H:%M:%S") {
+        Ok(formatted) => formatted.to_string(),
+        Err(_) => time.sec.to_string()
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut returned = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => returned.push_str("\\\""),
+            '\\' => returned.push_str("\\\\"),
+            '\n' => returned.push_str("\\n"),
+            '\r' => returned.push_str("\\r"),
+            '\t' => returned.push_str("\\t"),
+            c if (c as u32) < 0x20 => returned.push_str(&format!("\\u{:04x}", c as u32)),
+            c => returned.push(c)
+        }
+    }
+    returned.push('"');
+    returned
+}
+
+// listings are often piped into head, a reader that went away isn't an error
+pub fn print(text: &str) -> io::Result<()> {
+    match writeln!(io::stdout(), "{}", text) {
+        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result
+    }
+}
+
+// stored size against the real size, - for anything without contents
+fn ratio(stored: u64, size: u64) -> String {
+    if size == 0 {
+        "-".to_owned()
+    } else {
+        format!("{:.1}%", stored as f64 * 100.0 / size as f64)
+    }
+}
+
+impl ListEntry {
+    pub fn to_line(&self) -> String {
+        let attribute = &self.attribute;
+        let size = match self.kind {
+            FileType::CharDevice | FileType::BlockDevice => format!("{}, {}", (attribute.rdev >> 8) & 0xfff, (attribute.rdev & 0xff) | ((attribute.rdev >> 12) & 0xfff00)),
+            _ => attribute.size.to_string()
+        };
+        let ratio = if self.kind == FileType::RegularFile { ratio(self.stored, attribute.size) } else { "-".to_owned() };
+        let mut returned = format!("{:>8} {} {:>3} {:>5} {:>5} {:>10} {:>6} {} {}",
+            attribute.ino, mode_string(self.kind, attribute.perm), attribute.nlink, attribute.uid, attribute.gid,
+            size, ratio, format_time(attribute.mtime), self.path);
+        if self.kind == FileType::Symlink {
+            returned.push_str(" -> ");
+            returned.push_str(&self.target);
+        }
+        returned
+    }
+
+    pub fn to_json(&self) -> String {
+        let attribute = &self.attribute;
+        let mut returned = format!("{{\"path\": {}, \"inode\": {}, \"type\": \"{}\", \"mode\": \"{:04o}\", \"nlink\": {}, \"uid\": {}, \"gid\": {}, \"size\": {}, \"stored\": {}, \"mtime\": {}, \"atime\": {}, \"ctime\": {}",
+            json_string(&self.path), attribute.ino, kind_name(self.kind), attribute.perm & 0o7777, attribute.nlink, attribute.uid, attribute.gid,
+            attribute.size, self.stored, attribute.mtime.sec, attribute.atime.sec, attribute.ctime.sec);
+        match self.kind {
+            FileType::Symlink => returned.push_str(&format!(", \"target\": {}", json_string(&self.target))),
+            FileType::CharDevice | FileType::BlockDevice => returned.push_str(&format!(", \"rdev\": {}", attribute.rdev)),
+            _ => {}
+        }
+        returned.push('}');
+        returned
+    }
+}
+
+impl FuseStructure {
+    // every entry below the root, each directory's children in the order they are stored
+    pub fn list_entries(&self) -> io::Result<Vec<ListEntry>> {
+        let mut returned: Vec<ListEntry> = vec!();
+        self.list_directory(self.index.root, "", &mut returned)?;
+        Ok(returned)
+    }
+
+    fn list_directory(&self, node: u64, prefix: &str, entries: &mut Vec<ListEntry>) -> io::Result<()> {
+        for (name, child, kind) in self.children(node)? {
+            let attribute = *self.find_attribute(child)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}{} has no attributes", prefix, name)))?;
+            let path = format!("{}{}", prefix, name);
+            entries.push(ListEntry {
+                path: path.clone(),
+                kind,
+                attribute,
+                stored: self.find_file(child).map(|file| file.data_length).unwrap_or(0),
+                target: self.find_symlink(child).map(|symlink| symlink.target.clone()).unwrap_or_default(),
+            });
+            if kind == FileType::Directory {
+                self.list_directory(child, &(path + "/"), entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> io::Result<String> {
+        let lines: Vec<String> = self.list_entries()?.iter().map(|entry| entry.to_line()).collect();
+        Ok(lines.join("\n"))
+    }
+
+    pub fn list_json(&self) -> io::Result<String> {
+        let entries: Vec<String> = self.list_entries()?.iter().map(|entry| "  ".to_owned() + &entry.to_json()).collect();
+        if entries.is_empty() {
+            return Ok("[]".to_owned());
+        }
+        Ok(format!("[\n{}\n]", entries.join(",\n")))
+    }
+
+    // the format, the manifest and what the package holds
+    pub fn info(&self) -> String {
+        let data = self.data.as_slice();
+        let format = if data.starts_with(LEGACY_MAGIC) {
+            "rpack0 (legacy)".to_owned()
+        } else {
+            match BlobHeader::deserialize(data) {
+                Ok(header) => {
+                    let features: Vec<&str> = FEATURE_LABELS.iter()
+                        .filter(|(feature, _)| header.features & feature != 0)
+                        .map(|(_, name)| *name)
+                        .collect();
+                    format!("rpackv version {}, features: {}", header.version, features.join(", "))
+                }
+                Err(error) => error.to_string()
+            }
+        };
+
+        // contents shared by several files are only stored once
+        let mut stored: HashSet<(u64, u64)> = HashSet::new();
+        let mut stored_size = 0;
+        let mut size = 0;
+        for file in &self.files {
+            size += file.size;
+            if stored.insert((file.data_offset, file.data_length)) {
+                stored_size += file.data_length;
+            }
+        }
+        let names = self.directories.iter()
+            .flat_map(|directory| directory.node_types.iter())
+            .filter(|node_type| **node_type == NODE_FILE)
+            .count();
+
+        let manifest = &self.manifest;
+        let pairs = |pairs: &Vec<(String, String)>| pairs.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>().join(" ");
+        let mut lines: Vec<String> = vec!();
+        lines.push(format!("Format:        {}", format));
+        lines.push(format!("Name:          {}", manifest.name));
+        lines.push(format!("Version:       {}", manifest.version));
+        lines.push(format!("Entrypoint:    {}", manifest.entrypoint));
+        lines.push(format!("Interpreter:   {}", manifest.interpreter));
+        lines.push(format!("Arguments:     {}", manifest.args.join(" ")));
+        lines.push(format!("Workdir:       {}", manifest.workdir));
+        lines.push(format!("Environment:   {}", pairs(&manifest.env)));
+        lines.push(format!("Labels:        {}", pairs(&manifest.labels)));
+        lines.push(format!("Directories:   {}", self.directories.len()));
+        lines.push(format!("Files:         {} ({} names)", self.files.len(), names));
+        lines.push(format!("Symlinks:      {}", self.symlinks.len()));
+        lines.push(format!("Special files: {}", self.specials.len()));
+        lines.push(format!("Xattrs:        on {} entries", self.xattrs.len()));
+        lines.push(format!("Contents:      {} bytes, stored in {} ({})", size, stored_size, ratio(stored_size, size)));
+        lines.push(format!("Payload:       {} bytes", data.len()));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::tests::structure;
+
+    #[test]
+    fn modes_and_json_are_formatted_like_ls_and_json() {
+        assert_eq!(mode_string(FileType::Directory, 0o755), "drwxr-xr-x");
+        assert_eq!(mode_string(FileType::RegularFile, 0o100644), "-rw-r--r--");
+        assert_eq!(mode_string(FileType::RegularFile, 0o4755), "-rwsr-xr-x");
+        assert_eq!(mode_string(FileType::Directory, 0o1777), "drwxrwxrwt");
+        assert_eq!(mode_string(FileType::Symlink, 0o2640), "lrw-r-S---");
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+        assert_eq!(ratio(50, 200), "25.0%");
+        assert_eq!(ratio(0, 0), "-");
+        assert_eq!(format_time(Timespec::new(86400, 0)), "1970-01-02 00:00:00");
+    }
+
+    #[test]
+    fn listed_owners_are_mapped_like_the_mount() {
+        let mut fuse = structure();
+        fuse.apply_ownership(Ownership::Preserve);
+        assert!(fuse.list().unwrap().contains(&format!(" {:>5} {:>5} ", LEGACY_UID, LEGACY_GID)));
+        assert!(fuse.list_json().unwrap().contains(&format!("\"uid\": {}, \"gid\": {}", LEGACY_UID, LEGACY_GID)));
+
+        fuse.apply_ownership(Ownership::Fixed(1234, 56));
+        assert!(fuse.list().unwrap().contains(&format!(" {:>5} {:>5} ", 1234, 56)));
+        assert!(fuse.list_json().unwrap().contains("\"uid\": 1234, \"gid\": 56"));
+        assert!(!fuse.list().unwrap().contains(&format!(" {:>5} {:>5} ", LEGACY_UID, LEGACY_GID)));
+    }
+}
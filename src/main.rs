@@ -1,47 +1,326 @@
 use std::env;
 use std::ffi::OsStr;
-use std::fs::{create_dir, remove_dir};
-use std::path::Path;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::{thread, time};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 mod common;
+mod compression;
+mod extract;
+mod inspect;
+mod mountpoint;
+mod payload;
+mod process;
 use crate::common::*;
+use crate::mountpoint::Mountpoint;
+use crate::payload::*;
+
+// how long the kernel gets to initialize the mount before the runtime gives up
+const MOUNT_TIMEOUT: u64 = 10;
+
+fn usage() -> std::io::Result<()> {
+    eprintln!("Usage: rpackage [--owner preserve|user|<uid>:<gid>] [--mount-timeout <seconds>] [--mountpoint <dir>] [--cwd <dir>] [--extract-and-run] [--] [arguments for the packaged program...]");
+    eprintln!("       rpackage [--owner preserve|user|<uid>:<gid>] --extract [<dir> [<path in the package>...]]");
+    eprintln!("       rpackage [--owner preserve|user|<uid>:<gid>] --list [--json] | --info");
+    eprintln!("       rpackage [--owner preserve|user|<uid>:<gid>] [--mount-timeout <seconds>] [--daemonize] --mount [<dir>]");
+    Err(std::io::Error::from(std::io::ErrorKind::Other))
+}
+
+// the manifest's interpreter runs the entrypoint if it has one, entrypoints that can't be executed are run with bash like
+// packages without a manifest always were, anything else is executed itself so it can be a binary or have a #! line,
+// /bin/sh runs it when it is neither.
+// It starts in the caller's working directory unless the manifest or --cwd name another one, root is where the package is mounted
+fn entrypoint_command(manifest: &Manifest, executable: bool, root: &Path, cwd: Option<&Path>, user_args: &[String]) -> Command {
+    let path = root.join(manifest.entrypoint.trim_start_matches('/'));
+    let mut command = if !manifest.interpreter.is_empty() {
+        let mut command = Command::new(&manifest.interpreter);
+        command.arg(path);
+        command
+    } else if !executable {
+        let mut command = Command::new("bash");
+        command.arg(path);
+        command
+    } else {
+        Command::new(path)
+    };
+
+    command.args(&manifest.args)
+        .args(user_args)
+        .envs(manifest.env.iter().map(|(name, value)| (name, value)))
+        .env("RPACKAGE_ROOT", root)
+        .env("RPACKAGE_NAME", &manifest.name);
+    if let Ok(exe) = env::current_exe() {
+        command.env("RPACKAGE_EXE", exe);
+    }
+
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    } else if !manifest.workdir.is_empty() {
+        command.current_dir(root.join(manifest.workdir.trim_start_matches('/')));
+    }
+    command
+}
+
+// the blob appended to this runtime, mapped instead of read into memory
+fn map_own_payload() -> std::io::Result<BlobData> {
+    let map = map_file(Path::new("/proc/self/exe"))?;
+    match find_payload(&map) {
+        Some((offset, length)) => Ok(BlobData::Mapped(Arc::new(map), offset, length)),
+        None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "no rpackage payload found"))
+    }
+}
+
+// the attached package with its owners mapped like --owner says, the blob stays mapped so reading it again is cheap
+fn read_package(ownership: Ownership) -> std::io::Result<FuseStructure> {
+    let data = match map_own_payload() {
+        Ok(data) => data,
+        Err(_) => {
+            eprintln!("This runtime has no package attached, use generate to create one.");
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+        }
+    };
+
+    let mut fuse_structure = match FuseStructure::deserialize(data) {
+        Ok(fuse_structure) => fuse_structure,
+        Err(error) => {
+            eprintln!("The attached package is corrupt: {}", error);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error));
+        }
+    };
+    fuse_structure.apply_ownership(ownership);
+    Ok(fuse_structure)
+}
+
+// waits for the kernel to initialize the mount, a signal in the meantime stops waiting right away
+fn wait_for_mount(mounted: &mpsc::Receiver<()>, timeout: u64) -> std::io::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    while process::pending_signal() == 0 {
+        match mounted.recv_timeout(Duration::from_millis(200)) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() < deadline => {}
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("it was not ready after {} seconds", timeout)))
+        }
+    }
+    Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+}
+
+// a signal before the entrypoint runs stops the runtime with the code it would have been killed with.
+// The mountpoint is unmounted and removed first, exit doesn't run destructors
+fn stop(mountpoint: Mountpoint, signal: i32) -> ! {
+    drop(mountpoint);
+    std::process::exit(128 + signal);
+}
 
 fn main() -> std::io::Result<()>{
-    let mut data = include_bytes!("out.blob").to_vec();
+    let mut ownership = Ownership::User;
+    let mut user_args: Vec<String> = vec!();
+    let mut mount_timeout = MOUNT_TIMEOUT;
+    let mut mountpoint_path: Option<PathBuf> = None;
+    let mut cwd: Option<PathBuf> = None;
+    let mut extract_and_run = false;
+    let mut extract: Option<Vec<String>> = None; // the directory and the paths to extract
+    let mut list = false;
+    let mut info = false;
+    let mut json = false;
+    let mut mount_only = false;
+    let mut daemonize = false;
+
+    //runtime options come first, everything from the first argument that isn't one (or after --) is for the packaged program
+    let mut arguments = env::args().skip(1).peekable();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--owner" => {
+                ownership = match arguments.next().as_deref().and_then(Ownership::from_name) {
+                    Some(ownership) => ownership,
+                    None => return usage()
+                };
+            }
+            "--mount-timeout" => {
+                mount_timeout = match arguments.next().and_then(|timeout| timeout.parse().ok()) {
+                    Some(timeout) => timeout,
+                    None => return usage()
+                };
+            }
+            "--cwd" => {
+                cwd = match arguments.next() {
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => return usage()
+                };
+            }
+            "--mountpoint" => {
+                mountpoint_path = match arguments.next() {
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => return usage()
+                };
+            }
+            "--extract-and-run" => extract_and_run = true,
+            "--list" => list = true,
+            "--info" => info = true,
+            "--json" => json = true,
+            "--mount" => {
+                mount_only = true;
+                if let Some(path) = arguments.next_if(|arg| !arg.starts_with('-')) {
+                    mountpoint_path = Some(PathBuf::from(path));
+                }
+            }
+            "--daemonize" => daemonize = true,
+            "--extract" => {
+                extract = Some(arguments.collect());
+                break;
+            }
+            "--" => {
+                user_args.extend(arguments);
+                break;
+            }
+            _ => {
+                user_args.push(arg);
+                user_args.extend(arguments);
+                break;
+            }
+        }
+    }
+    if json && !list {
+        return usage();
+    }
+    if daemonize && !mount_only {
+        return usage();
+    }
+
+    //--list shows the owners as --owner maps them, like the mounted package does
+    let fuse_structure = read_package(ownership)?;
+    let executable = fuse_structure.resolve_path(&fuse_structure.manifest.entrypoint)
+        .and_then(|node| fuse_structure.find_attribute(node))
+        .map(|attribute| attribute.perm & 0o111 != 0)
+        .unwrap_or(false);
+    let manifest = fuse_structure.manifest.clone();
 
-    //TODO: handle errors deserializing
-    let fuse_structure = FuseStructure::deserialize(&mut data).unwrap();
+    if info {
+        return inspect::print(&fuse_structure.info());
+    }
+    if list {
+        return inspect::print(&if json { fuse_structure.list_json()? } else { fuse_structure.list()? });
+    }
+
+    if let Some(extract) = extract {
+        //into the directory given or one named after the package, nothing is run
+        let destination = match extract.first() {
+            Some(directory) => PathBuf::from(directory),
+            None if extract::safe_entry_name(&manifest.name) => PathBuf::from(&manifest.name),
+            None => PathBuf::from("package")
+        };
+        create_dir_all(&destination)?;
+        let paths = extract.get(1..).unwrap_or(&[]);
+        if let Err(error) = fuse_structure.extract(&destination, paths, true) {
+            eprintln!("Could not extract the package: {}", error);
+            return Err(error);
+        }
+        eprintln!("Extracted the package to {}", destination.display());
+        return Ok(());
+    }
 
     let options = ["-o", "ro", "-o", "fsname=rpackage"]
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
 
-    create_dir(Path::new("./fusemount"))?;
-    let mountpoint = &"./fusemount";
-
-    let h = thread::spawn(move || {
-        let sec = time::Duration::from_millis(1000);
-        thread::sleep(sec); // wait for the structure to mount
-        env::set_current_dir(Path::new("./fusemount"));
-        let output = Command::new("bash")
-            .arg("./startup.sh")
-            .output()
-            .expect("command failed to start");
-
-        println!("Hello, world! {}\nStderr: {}", String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap());
-        env::set_current_dir(Path::new(".."));
-        Command::new("fusermount")
-            .arg("-u")
-            .arg("./fusemount")
-            .output()
-            .expect("command failed to start");
-    });
-    fuse::mount(fuse_structure, mountpoint, options.as_slice()).unwrap();
-    h.join();
-    remove_dir("./fusemount");
-
-    Ok(())
+    if mount_only && extract_and_run {
+        return usage();
+    }
+    //the parent exits once the package is mounted, the child keeps serving it
+    let detach = if mount_only && daemonize { Some(process::daemonize()?) } else { None };
+
+    //from here on the runtime only passes these signals on, it exits once the entrypoint has. Until the entrypoint runs they stop it
+    process::forward_signals();
+
+    //the mountpoint is unmounted and removed when it is dropped, on errors and panics as well
+    let mut mountpoint = match &mountpoint_path {
+        Some(path) => Mountpoint::at(path),
+        None => Mountpoint::private(&manifest.name)
+    }?;
+
+    let (unmount, unmounted) = mpsc::channel::<()>();
+    //without FUSE the package is copied out instead, the copy is removed like a mount would be unmounted
+    let mut extracted = extract_and_run;
+    if !extract_and_run && !mountpoint::fuse_available() {
+        if mount_only {
+            eprintln!("Mounting needs FUSE, which is not available here. --extract unpacks the package instead.");
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported));
+        }
+        eprintln!("FUSE is not available, extracting the package to {} instead.", mountpoint.path.display());
+        extracted = true;
+    }
+
+    //the structure is moved into the session, extracting after a failed mount reads the package again
+    let mut fuse_structure = Some(fuse_structure);
+    if !extracted {
+        let (ready, mounted) = mpsc::channel();
+        let mut filesystem = fuse_structure.take().unwrap();
+        filesystem.on_init = Some(ready);
+        filesystem.on_unmount = Some(unmount);
+        let result = match mountpoint.mount(filesystem, options.as_slice()) {
+            Ok(()) => wait_for_mount(&mounted, mount_timeout),
+            Err(error) => Err(error)
+        };
+        let signal = process::take_pending_signal();
+        if signal != 0 {
+            stop(mountpoint, signal);
+        }
+        if let Err(error) = result {
+            if mount_only {
+                eprintln!("Could not mount the package: {}. Is FUSE working on this system?", error);
+                return Err(error);
+            }
+            eprintln!("Could not mount the package: {}, extracting it to {} instead.", error, mountpoint.path.display());
+            mountpoint.unmount();
+            extracted = true;
+        }
+    }
+
+    if extracted {
+        let fuse_structure = match fuse_structure {
+            Some(fuse_structure) => fuse_structure,
+            None => read_package(ownership)?
+        };
+        let result = mountpoint.extract(&fuse_structure);
+        let signal = process::take_pending_signal();
+        if signal != 0 {
+            stop(mountpoint, signal);
+        }
+        if let Err(error) = result {
+            eprintln!("Could not extract the package: {}", error);
+            return Err(error);
+        }
+    }
+
+    if mount_only {
+        println!("{}", mountpoint.path.display());
+        if let Some(ready) = detach {
+            process::detached(ready);
+        }
+        //mounted until a signal like Ctrl-C comes in or someone else unmounts it
+        loop {
+            match unmounted.recv_timeout(Duration::from_millis(200)) {
+                Err(mpsc::RecvTimeoutError::Timeout) if process::take_pending_signal() == 0 => {}
+                _ => break
+            }
+        }
+        return Ok(());
+    }
+
+    let mut command = entrypoint_command(&manifest, executable, &mountpoint.path, cwd.as_deref(), &user_args);
+    //stdin, stdout and stderr are inherited so the packaged program talks to the terminal directly
+    let code = match process::run(&mut command) {
+        Ok(status) => process::exit_code(status),
+        Err(error) => {
+            eprintln!("Could not start the entrypoint: {}", error);
+            127
+        }
+    };
+    //unmounting only starts once the entrypoint and with it anything using the mount has exited
+    //exit doesn't run destructors
+    drop(mountpoint);
+
+    std::process::exit(code);
 }
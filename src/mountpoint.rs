@@ -0,0 +1,191 @@
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use fuse::{BackgroundSession, Filesystem};
+use crate::common::FuseStructure;
+use crate::extract::{remove_tree, safe_entry_name};
+
+// where the package is mounted, unmounted and removed again however the runtime exits short of being killed outright
+pub struct Mountpoint {
+    pub path: PathBuf,
+    pub created: bool, // only directories the runtime made itself are removed
+    pub session: Option<BackgroundSession<'static>>,
+    pub extracted: Vec<PathBuf>, // entries an extracted package put in the directory
+}
+
+// the directory private mountpoints are made in, the first of $XDG_RUNTIME_DIR, $TMPDIR and /tmp that exists
+fn runtime_directory() -> PathBuf {
+    for variable in ["XDG_RUNTIME_DIR", "TMPDIR"].iter() {
+        if let Some(directory) = env::var_os(variable) {
+            let directory = PathBuf::from(directory);
+            if directory.is_dir() {
+                return directory;
+            }
+        }
+    }
+    PathBuf::from("/tmp")
+}
+
+// package names end up in a path, anything but letters, digits, dots, dashes and underscores is replaced
+fn safe_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "package".to_owned(),
+        name => name.to_owned()
+    }
+}
+
+// mounting needs the kernel's FUSE device, and fusermount unless the runtime is root
+pub fn fuse_available() -> bool {
+    if !Path::new("/dev/fuse").exists() {
+        return false;
+    }
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).any(|directory| directory.join("fusermount").is_file()))
+        .unwrap_or(false)
+}
+
+// umount works for root, everyone else needs fusermount. A lazy unmount detaches a mount that is still in use
+fn unmount(path: &Path, lazy: bool) -> bool {
+    if let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) {
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+        if unsafe { libc::umount2(c_path.as_ptr(), flags) } == 0 {
+            return true;
+        }
+    }
+
+    let mut command = Command::new("fusermount");
+    command.arg("-u");
+    if lazy {
+        command.arg("-z");
+    }
+    command.arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+impl Mountpoint {
+    // a new directory only the current user can get into, like $XDG_RUNTIME_DIR/rpackage.<name>.<random>
+    pub fn private(name: &str) -> io::Result<Mountpoint> {
+        let mut template = runtime_directory();
+        template.push(format!("rpackage.{}.XXXXXX", safe_name(name)));
+        let template = CString::new(template.into_os_string().into_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let raw = template.into_raw();
+        let created = unsafe { libc::mkdtemp(raw) };
+        let path = unsafe { CString::from_raw(raw) };
+        if created.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Mountpoint {
+            path: PathBuf::from(OsStr::from_bytes(path.as_bytes())),
+            created: true,
+            session: None,
+            extracted: vec!(),
+        })
+    }
+
+    // a directory given on the command line, it is made if it doesn't exist yet and only removed then
+    pub fn at(path: &Path) -> io::Result<Mountpoint> {
+        let created = match fs::create_dir(path) {
+            Ok(()) => true,
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => false,
+            Err(error) => return Err(error)
+        };
+
+        Ok(Mountpoint {
+            path: fs::canonicalize(path)?,
+            created,
+            session: None,
+            extracted: vec!(),
+        })
+    }
+
+    pub fn mount<FS: Filesystem + Send + 'static>(&mut self, filesystem: FS, options: &[&OsStr]) -> io::Result<()> {
+        self.session = Some(unsafe { fuse::spawn_mount(filesystem, &self.path, options)? });
+        Ok(())
+    }
+
+    // the directory stays, empty again unless something was extracted into it
+    pub fn unmount(&mut self) {
+        if let Some(session) = self.session.take() {
+            // unmounted before the session is dropped, its own unmount has no lazy fallback. Dropping it then tries to unmount
+            // again, which fails quietly, and waits for its thread. That ends and drops the filesystem and the fuse device once
+            // the kernel has let go of the mount, after a lazy unmount only when the last program using it is done with it
+            if !unmount(&self.path, false) {
+                // something left behind by the entrypoint still uses the mount, it goes away once that is done
+                unmount(&self.path, true);
+            }
+            drop(session);
+        }
+    }
+
+    // copies the package into the directory instead of mounting it, only entries that weren't there before are removed again
+    pub fn extract(&mut self, fuse: &FuseStructure) -> io::Result<()> {
+        for (name, _, _) in fuse.children(fuse.index.root)? {
+            let path = self.path.join(name);
+            if safe_entry_name(name) && fs::symlink_metadata(&path).is_err() {
+                self.extracted.push(path);
+            }
+        }
+        fuse.extract(&self.path, &[], false)
+    }
+}
+
+impl Drop for Mountpoint {
+    fn drop(&mut self) {
+        self.unmount();
+        for path in &self.extracted {
+            let _ = remove_tree(path);
+        }
+        if self.created {
+            let _ = fs::remove_dir(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_mountpoints_are_unique_and_removed() {
+        let first = Mountpoint::private("my app/1.0").unwrap();
+        let second = Mountpoint::private("my app/1.0").unwrap();
+        assert_ne!(first.path, second.path);
+        assert!(first.path.file_name().unwrap().to_str().unwrap().starts_with("rpackage.my_app_1.0."));
+
+        let path = first.path.clone();
+        assert!(path.is_dir());
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn existing_mountpoints_are_kept() {
+        let parent = Mountpoint::private("test").unwrap();
+        let existing = Mountpoint::at(&parent.path).unwrap();
+        assert!(!existing.created);
+        drop(existing);
+        assert!(parent.path.is_dir());
+
+        let inside = Mountpoint::at(&parent.path.join("made")).unwrap();
+        assert!(inside.created);
+        drop(inside);
+        assert!(!parent.path.join("made").exists());
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use byteorder::*;
+use memmap2::Mmap;
+
+// A packaged binary is the runtime stub followed by the blob and a fixed size trailer:
+// [stub][blob][blob offset: u64][blob length: u64][magic: 8 bytes]
+pub const TRAILER_MAGIC: &[u8; 8] = b"rpacktrl";
+pub const TRAILER_SIZE: usize = 24;
+
+#[derive(Clone)]
+pub enum BlobData {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>, usize, usize), // the mapping, where the blob starts in it and how long it is
+}
+
+impl BlobData {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            BlobData::Owned(data) => data.as_slice(),
+            BlobData::Mapped(map, offset, length) => &map[*offset..*offset + *length]
+        }
+    }
+}
+
+// returns the offset and length of the blob inside of data
+pub fn find_payload(data: &[u8]) -> Option<(usize, usize)> {
+    if data.len() < TRAILER_SIZE {
+        return None;
+    }
+    let trailer = &data[data.len() - TRAILER_SIZE..];
+    if &trailer[16..] != TRAILER_MAGIC {
+        return None;
+    }
+
+    let offset = BigEndian::read_u64(&trailer[0..8]) as usize;
+    let length = BigEndian::read_u64(&trailer[8..16]) as usize;
+    let end = offset.checked_add(length)?;
+    if end > data.len() - TRAILER_SIZE {
+        return None;
+    }
+
+    Some((offset, length))
+}
+
+// the package is opened read only and is not expected to change while it is mapped
+pub fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    unsafe { Mmap::map(&file) }
+}
@@ -0,0 +1,237 @@
+use std::io;
+use std::ptr;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use libc::{c_int, SIGHUP, SIGINT, SIGTERM, SIGTTOU, SIG_DFL, SIG_IGN, STDIN_FILENO};
+
+// signals the runtime passes on to the packaged program instead of dying from them
+pub const FORWARDED_SIGNALS: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+// the process group of the running entrypoint, 0 while there is none
+static CHILD_GROUP: AtomicI32 = AtomicI32::new(0);
+// a signal that came in while no entrypoint was running, it stops the runtime before it starts one
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: c_int) {
+    let group = CHILD_GROUP.load(Ordering::SeqCst);
+    if group > 0 {
+        unsafe { libc::kill(-group, signal); }
+    } else {
+        PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+    }
+}
+
+pub fn forward_signals() {
+    for signal in FORWARDED_SIGNALS.iter() {
+        unsafe { libc::signal(*signal, forward_signal as *const () as libc::sighandler_t); }
+    }
+}
+
+// a signal that came in while no entrypoint was running, 0 if none did
+pub fn pending_signal() -> c_int {
+    PENDING_SIGNAL.load(Ordering::SeqCst)
+}
+
+pub fn take_pending_signal() -> c_int {
+    PENDING_SIGNAL.swap(0, Ordering::SeqCst)
+}
+
+// holds the forwarded signals back, or lets them through again with SIG_UNBLOCK
+fn block_signals(how: c_int) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in FORWARDED_SIGNALS.iter() {
+            libc::sigaddset(&mut set, *signal);
+        }
+        libc::pthread_sigmask(how, &set, ptr::null_mut());
+    }
+}
+
+// forks before anything starts a thread, which wouldn't survive it. The parent waits until the child calls detached and
+// exits with 0 then, or with 1 if the child exits before that. The child gets the pipe to call detached with
+pub fn daemonize() -> io::Result<c_int> {
+    let mut pipe: [c_int; 2] = [0; 2];
+    if unsafe { libc::pipe(pipe.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe {
+            libc::close(pipe[0]);
+            libc::setsid();
+            Ok(pipe[1])
+        },
+        _ => unsafe {
+            libc::close(pipe[1]);
+            let mut ready: u8 = 0;
+            let read = libc::read(pipe[0], &mut ready as *mut u8 as *mut libc::c_void, 1);
+            std::process::exit(if read == 1 { 0 } else { 1 });
+        }
+    }
+}
+
+// lets the parent exit and lets go of its terminal and working directory
+pub fn detached(ready: c_int) {
+    unsafe {
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null >= 0 {
+            for fd in [STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+                libc::dup2(null, *fd);
+            }
+            libc::close(null);
+        }
+        libc::chdir(b"/\0".as_ptr() as *const libc::c_char);
+        libc::write(ready, b"1".as_ptr() as *const libc::c_void, 1);
+        libc::close(ready);
+    }
+}
+
+// whether the runtime owns the terminal, only then can it hand it to the entrypoint
+fn in_foreground() -> bool {
+    unsafe { libc::isatty(STDIN_FILENO) == 1 && libc::tcgetpgrp(STDIN_FILENO) == libc::getpgrp() }
+}
+
+// tcsetpgrp from a process that isn't in the foreground is stopped with SIGTTOU unless that is ignored
+fn give_terminal(group: libc::pid_t) {
+    unsafe {
+        libc::signal(SIGTTOU, SIG_IGN);
+        libc::tcsetpgrp(STDIN_FILENO, group);
+        libc::signal(SIGTTOU, SIG_DFL);
+    }
+}
+
+// the child gets a process group of its own and the terminal if the runtime has it
+fn prepare(command: &mut Command, foreground: bool) {
+    command.process_group(0);
+    // the child starts out with the mask of the thread that forks it, which holds the signals back while it does
+    unsafe {
+        command.pre_exec(|| {
+            block_signals(libc::SIG_UNBLOCK);
+            Ok(())
+        });
+    }
+    if foreground {
+        // done in the child as well so it never reads from the terminal before it owns it
+        unsafe {
+            command.pre_exec(|| {
+                give_terminal(libc::getpid());
+                Ok(())
+            });
+        }
+    }
+}
+
+// an executable without a #! line can't be executed itself, /bin/sh runs it then like a shell would
+fn spawn(command: &mut Command, foreground: bool) -> io::Result<Child> {
+    match command.spawn() {
+        Err(error) if error.raw_os_error() == Some(libc::ENOEXEC) => {
+            let mut shell = Command::new("/bin/sh");
+            shell.arg(command.get_program()).args(command.get_args());
+            for (name, value) in command.get_envs() {
+                match value {
+                    Some(value) => shell.env(name, value),
+                    None => shell.env_remove(name)
+                };
+            }
+            if let Some(directory) = command.get_current_dir() {
+                shell.current_dir(directory);
+            }
+            prepare(&mut shell, foreground);
+            shell.spawn()
+        }
+        result => result
+    }
+}
+
+// runs the command in a process group of its own, in the terminal's foreground if the runtime was, and waits for it
+pub fn run(command: &mut Command) -> io::Result<ExitStatus> {
+    let foreground = in_foreground();
+    prepare(command, foreground);
+
+    // a signal either comes in before the entrypoint is started and stops it from being started, as if it had killed it,
+    // or is held back until the entrypoint's group is known and then passed on to it
+    block_signals(libc::SIG_BLOCK);
+    let pending = take_pending_signal();
+    if pending != 0 {
+        block_signals(libc::SIG_UNBLOCK);
+        return Ok(ExitStatus::from_raw(pending));
+    }
+    let mut child = match spawn(command, foreground) {
+        Ok(child) => child,
+        Err(error) => {
+            block_signals(libc::SIG_UNBLOCK);
+            return Err(error);
+        }
+    };
+    let group = child.id() as i32;
+    CHILD_GROUP.store(group, Ordering::SeqCst);
+    block_signals(libc::SIG_UNBLOCK);
+    if foreground {
+        give_terminal(group);
+    }
+
+    let status = child.wait();
+    CHILD_GROUP.store(0, Ordering::SeqCst);
+    if foreground {
+        give_terminal(unsafe { libc::getpgrp() });
+    }
+    status
+}
+
+// the exit code a shell would report, 128 + the signal for a program that was killed
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+
+    // the entrypoint's group and the pending signal are process wide, so tests that run something take turns
+    static RUNNING: Mutex<()> = Mutex::new(());
+
+    fn shell(script: &str) -> i32 {
+        exit_code(run(Command::new("sh").arg("-c").arg(script)).unwrap())
+    }
+
+    #[test]
+    fn exit_codes_are_passed_on() {
+        let _running = RUNNING.lock().unwrap();
+        assert_eq!(shell("exit 0"), 0);
+        assert_eq!(shell("exit 3"), 3);
+        assert_eq!(shell("kill -TERM $$"), 128 + SIGTERM);
+    }
+
+    #[test]
+    fn a_signal_before_the_start_stops_it() {
+        let _running = RUNNING.lock().unwrap();
+        forward_signals();
+        unsafe { libc::raise(SIGTERM); }
+        let marker = std::env::temp_dir().join(format!("rpackage-started-{}", std::process::id()));
+        assert_eq!(shell(&format!("touch {}", marker.display())), 128 + SIGTERM);
+        assert!(!marker.exists());
+        assert_eq!(pending_signal(), 0);
+        assert_eq!(shell("exit 3"), 3);
+    }
+
+    #[test]
+    fn executables_without_a_shebang_are_run_by_the_shell() {
+        let _running = RUNNING.lock().unwrap();
+        let script = std::env::temp_dir().join(format!("rpackage-no-shebang-{}", std::process::id()));
+        fs::write(&script, "exit $(($1 + 2))\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let code = exit_code(run(Command::new(&script).arg("3")).unwrap());
+        fs::remove_file(&script).unwrap();
+        assert_eq!(code, 5);
+    }
+}
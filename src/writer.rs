@@ -0,0 +1,661 @@
+use fuse::*;
+use std::collections::HashMap;
+use crate::common::*;
+use crate::payload::BlobData;
+
+// the generate side of the blob format, the runtime only ever reads blobs
+pub trait FuseWrite<T> {
+    fn find_by_node(container:&Vec<T>, node:u64) -> Option<&T>;
+    fn serialize(&self) -> Vec<u8>;
+}
+
+impl FuseFile {
+    pub fn find_by_node(container:&Vec<FuseFile>, node:u64) -> Option<&FuseFile> {
+        for file in container {
+            if file.node == node {
+                return Some(file);
+            }
+        }
+
+        return None;
+    }
+
+    // v1 file entries point into the data section instead of carrying their contents inline
+    pub fn serialize_entry(&self, data_offset: u64) -> Vec<u8> {
+        let mut returned:Vec<u8> = vec!();
+
+        returned.extend((self.name.len() as u64).to_be_bytes().to_vec());
+        returned.extend(self.name.as_bytes().to_vec());
+
+        returned.extend(self.node.to_be_bytes().to_vec());
+
+        returned.extend(data_offset.to_be_bytes().to_vec());
+        returned.extend(self.data_length.to_be_bytes().to_vec());
+
+        returned.extend(self.size.to_be_bytes().to_vec());
+        returned.extend(self.block_size.to_be_bytes().to_vec());
+        for block in &self.blocks {
+            returned.extend(block.length.to_be_bytes().to_vec());
+            returned.push(block.codec.to_u8());
+        }
+
+        returned
+    }
+}
+
+impl FuseWrite<FuseDirectory> for FuseDirectory {
+    fn find_by_node(container:&Vec<FuseDirectory>, node:u64) -> Option<&FuseDirectory> {
+        for directory in container {
+            if directory.node == node {
+                return Some(directory);
+            }
+        }
+        None
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut returned:Vec<u8> = vec!();
+
+        let name_size = self.name.len();
+        let name = &self.name;
+
+        returned.extend(name_size.to_be_bytes().to_vec());
+        returned.extend(name.as_bytes().to_vec());
+
+        returned.extend(self.node.to_be_bytes().to_vec());
+
+        let nodes_size = self.nodes.len();
+        returned.extend(nodes_size.to_be_bytes().to_vec());
+
+        for node in &self.nodes {
+            returned.extend(node.to_be_bytes().to_vec());
+        }
+
+        for node_type in &self.node_types {
+            returned.push(*node_type);
+        }
+
+        returned.extend(self.parent_node.to_be_bytes().to_vec());
+
+        if self.is_root {
+            returned.push(1);
+        } else {
+            returned.push(0);
+        }
+
+        returned
+    }
+}
+
+impl FuseWrite<FuseSymlink> for FuseSymlink {
+    fn find_by_node(container:&Vec<FuseSymlink>, node:u64) -> Option<&FuseSymlink> {
+        container.iter().find(|symlink| symlink.node == node)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut returned:Vec<u8> = vec!();
+
+        returned.extend((self.name.len() as u64).to_be_bytes().to_vec());
+        returned.extend(self.name.as_bytes().to_vec());
+
+        returned.extend(self.node.to_be_bytes().to_vec());
+
+        returned.extend((self.target.len() as u64).to_be_bytes().to_vec());
+        returned.extend(self.target.as_bytes().to_vec());
+
+        returned
+    }
+}
+
+impl FuseWrite<FuseSpecial> for FuseSpecial {
+    fn find_by_node(container:&Vec<FuseSpecial>, node:u64) -> Option<&FuseSpecial> {
+        container.iter().find(|special| special.node == node)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut returned:Vec<u8> = vec!();
+
+        returned.extend((self.name.len() as u64).to_be_bytes().to_vec());
+        returned.extend(self.name.as_bytes().to_vec());
+
+        returned.extend(self.node.to_be_bytes().to_vec());
+
+        returned.push(FuseStructure::kind_to_u8(self.kind));
+
+        returned
+    }
+}
+
+impl FuseWrite<FileAttr> for FileAttr {
+    fn find_by_node(container:&Vec<FileAttr>, node:u64) -> Option<&FileAttr> {
+        for attribute in container {
+            if attribute.ino == node {
+                return Some(attribute);
+            }
+        }
+
+        return None;
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut returned:Vec<u8> = vec!();
+
+        returned.extend(self.ino.to_be_bytes().to_vec());
+
+        returned.extend(self.size.to_be_bytes().to_vec());
+
+        returned.extend(self.atime.sec.to_be_bytes().to_vec());
+        returned.extend(self.atime.nsec.to_be_bytes().to_vec());
+
+        returned.extend(self.mtime.sec.to_be_bytes().to_vec());
+        returned.extend(self.mtime.nsec.to_be_bytes().to_vec());
+
+        returned.extend(self.ctime.sec.to_be_bytes().to_vec());
+        returned.extend(self.ctime.nsec.to_be_bytes().to_vec());
+
+        returned.extend(self.perm.to_be_bytes().to_vec());
+
+        returned.push(FuseStructure::kind_to_u8(self.kind));
+
+        returned
+    }
+}
+
+impl Manifest {
+    // arguments are stored in order as arg, variables as env.<name> and labels as label.<key>, keys a reader doesn't know are skipped
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut returned: Vec<(String, String)> = vec!();
+        returned.push(("name".to_owned(), self.name.clone()));
+        returned.push(("version".to_owned(), self.version.clone()));
+        returned.push(("entrypoint".to_owned(), self.entrypoint.clone()));
+        if !self.interpreter.is_empty() {
+            returned.push(("interpreter".to_owned(), self.interpreter.clone()));
+        }
+        for arg in &self.args {
+            returned.push(("arg".to_owned(), arg.clone()));
+        }
+        if !self.workdir.is_empty() {
+            returned.push(("workdir".to_owned(), self.workdir.clone()));
+        }
+        for (name, value) in &self.env {
+            returned.push(("env.".to_owned() + name.as_str(), value.clone()));
+        }
+        for (key, value) in &self.labels {
+            returned.push(("label.".to_owned() + key.as_str(), value.clone()));
+        }
+        returned
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut returned: Vec<u8> = vec!();
+        let entries = self.entries();
+
+        returned.extend((entries.len() as u64).to_be_bytes().to_vec());
+        for (key, value) in entries {
+            returned.extend((key.len() as u64).to_be_bytes().to_vec());
+            returned.extend(key.as_bytes().to_vec());
+            returned.extend((value.len() as u64).to_be_bytes().to_vec());
+            returned.extend(value.as_bytes().to_vec());
+        }
+
+        returned
+    }
+}
+
+impl BlobHeader {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut returned: Vec<u8> = vec!();
+
+        returned.extend(BLOB_MAGIC.to_vec());
+        returned.extend(self.version.to_be_bytes().to_vec());
+        returned.extend(self.flags.to_be_bytes().to_vec());
+        returned.extend(self.features.to_be_bytes().to_vec());
+        returned.extend((self.sections.len() as u32).to_be_bytes().to_vec());
+
+        for section in &self.sections {
+            returned.extend(section.kind.to_be_bytes().to_vec());
+            returned.extend(section.offset.to_be_bytes().to_vec());
+            returned.extend(section.length.to_be_bytes().to_vec());
+        }
+
+        returned
+    }
+}
+
+impl FuseStructure {
+    // appends stored file contents to the blob and returns where they start
+    pub fn push_data(&mut self, stored: &[u8]) -> u64 {
+        // a mapped blob is read only, writing to it copies it into memory first
+        if let BlobData::Mapped(..) = self.data {
+            self.data = BlobData::Owned(self.data.as_slice().to_vec());
+        }
+        match &mut self.data {
+            BlobData::Owned(data) => {
+                let offset = data.len() as u64;
+                data.extend(stored);
+                offset
+            }
+            BlobData::Mapped(..) => unreachable!()
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut directories: Vec<u8> = vec!();
+        let mut files: Vec<u8> = vec!();
+        let mut attributes: Vec<u8> = vec!();
+        let mut file_data: Vec<u8> = vec!();
+
+        directories.extend((self.directories.len() as u64).to_be_bytes().to_vec());
+        for directory in &self.directories {
+            directories.extend(directory.serialize());
+        }
+
+        // files sharing their contents keep sharing them in the written blob
+        let mut written: HashMap<(u64, u64), u64> = HashMap::new();
+        files.extend((self.files.len() as u64).to_be_bytes().to_vec());
+        for file in &self.files {
+            let offset = *written.entry((file.data_offset, file.data_length)).or_insert_with(|| {
+                let offset = file_data.len() as u64;
+                file_data.extend(file.stored(self.data.as_slice()));
+                offset
+            });
+            files.extend(file.serialize_entry(offset));
+        }
+
+        attributes.extend((self.attributes.len() as u64).to_be_bytes().to_vec());
+        for attribute in &self.attributes {
+            attributes.extend(attribute.serialize());
+            attributes.extend(FuseStructure::serialize_attribute_rest(attribute));
+        }
+
+        let mut contents = vec!(
+            (SECTION_DIRECTORIES, directories),
+            (SECTION_FILES, files),
+            (SECTION_ATTRIBUTES, attributes),
+            (SECTION_DATA, file_data),
+        );
+
+        let mut header = BlobHeader {
+            version: FORMAT_VERSION,
+            flags: 0,
+            features: FEATURE_BLOCKS | FEATURE_FULL_ATTRIBUTES,
+            sections: vec!(),
+        };
+
+        // optional sections are only written when they have something in them
+        let renamed: Vec<&FuseDirectory> = self.directories.iter().filter(|directory| self.has_own_names(directory)).collect();
+        if !renamed.is_empty() {
+            let mut names: Vec<u8> = vec!();
+            names.extend((renamed.len() as u64).to_be_bytes().to_vec());
+            for directory in renamed {
+                names.extend(directory.node.to_be_bytes().to_vec());
+                names.extend((directory.names.len() as u64).to_be_bytes().to_vec());
+                for name in &directory.names {
+                    names.extend((name.len() as u64).to_be_bytes().to_vec());
+                    names.extend(name.as_bytes().to_vec());
+                }
+            }
+            contents.push((SECTION_NAMES, names));
+            header.features |= FEATURE_NAMES;
+        }
+
+        if !self.xattrs.is_empty() {
+            // sorted by node so the same tree always gives the same blob
+            let mut nodes: Vec<&u64> = self.xattrs.keys().collect();
+            nodes.sort();
+
+            let mut xattrs: Vec<u8> = vec!();
+            xattrs.extend((nodes.len() as u64).to_be_bytes().to_vec());
+            for node in nodes {
+                let node_xattrs = &self.xattrs[node];
+                xattrs.extend(node.to_be_bytes().to_vec());
+                xattrs.extend((node_xattrs.len() as u64).to_be_bytes().to_vec());
+                for (name, value) in node_xattrs {
+                    xattrs.extend((name.len() as u64).to_be_bytes().to_vec());
+                    xattrs.extend(name.as_bytes().to_vec());
+                    xattrs.extend((value.len() as u64).to_be_bytes().to_vec());
+                    xattrs.extend(value.to_vec());
+                }
+            }
+            contents.push((SECTION_XATTRS, xattrs));
+            header.features |= FEATURE_XATTRS;
+        }
+
+        if self.manifest != Manifest::new() {
+            contents.push((SECTION_MANIFEST, self.manifest.serialize()));
+            header.features |= FEATURE_MANIFEST;
+        }
+
+        if !self.specials.is_empty() {
+            let mut specials: Vec<u8> = vec!();
+            specials.extend((self.specials.len() as u64).to_be_bytes().to_vec());
+            for special in &self.specials {
+                specials.extend(special.serialize());
+            }
+            contents.push((SECTION_SPECIAL_FILES, specials));
+            header.features |= FEATURE_SPECIAL_FILES;
+        }
+
+        if !self.symlinks.is_empty() {
+            let mut symlinks: Vec<u8> = vec!();
+            symlinks.extend((self.symlinks.len() as u64).to_be_bytes().to_vec());
+            for symlink in &self.symlinks {
+                symlinks.extend(symlink.serialize());
+            }
+            contents.push((SECTION_SYMLINKS, symlinks));
+            header.features |= FEATURE_SYMLINKS;
+        }
+        let mut offset = (HEADER_SIZE + SECTION_ENTRY_SIZE * contents.len()) as u64;
+        for (kind, section) in &contents {
+            header.sections.push(BlobSection {
+                kind: *kind,
+                offset,
+                length: section.len() as u64,
+            });
+            offset += section.len() as u64;
+        }
+
+        let mut returned = header.serialize();
+        for (_, section) in contents {
+            returned.extend(section);
+        }
+
+        returned
+    }
+
+    // the fields the original attribute record left out, written right after it
+    pub fn serialize_attribute_rest(attribute: &FileAttr) -> Vec<u8> {
+        let mut returned: Vec<u8> = vec!();
+
+        returned.extend(attribute.blocks.to_be_bytes().to_vec());
+        returned.extend(attribute.crtime.sec.to_be_bytes().to_vec());
+        returned.extend(attribute.crtime.nsec.to_be_bytes().to_vec());
+        returned.extend(attribute.nlink.to_be_bytes().to_vec());
+        returned.extend(attribute.uid.to_be_bytes().to_vec());
+        returned.extend(attribute.gid.to_be_bytes().to_vec());
+        returned.extend(attribute.rdev.to_be_bytes().to_vec());
+        returned.extend(attribute.flags.to_be_bytes().to_vec());
+
+        returned
+    }
+
+    // a directory only needs its own names when one differs from the name its child was recorded with
+    fn has_own_names(&self, directory: &FuseDirectory) -> bool {
+        directory.names.iter().enumerate().any(|(i, name)| {
+            match self.find_entry(directory.nodes[i], directory.node_types[i]) {
+                Some((recorded, _)) => recorded != name,
+                None => true
+            }
+        })
+    }
+
+    // how a FileType is written in attribute and special file records, blobs only had the first three before
+    pub fn kind_to_u8(kind: FileType) -> u8 {
+        match kind {
+            FileType::Directory => 0,
+            FileType::RegularFile => 1,
+            FileType::Symlink => 2,
+            FileType::NamedPipe => 3,
+            FileType::CharDevice => 4,
+            FileType::BlockDevice => 5,
+            FileType::Socket => 6
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+    use libc::{c_int, ENOENT, ENODATA, ERANGE};
+    use crate::common::tests::*;
+
+    // what the filesystem answered to an xattr request
+    #[derive(Debug, PartialEq)]
+    enum XattrAnswer {
+        Size(u32),
+        Data(Vec<u8>),
+        Error(c_int),
+    }
+
+    struct FakeXattrReply<'a>(&'a mut Option<XattrAnswer>);
+
+    impl<'a> XattrReply for FakeXattrReply<'a> {
+        fn size(self, size: u32) {
+            *self.0 = Some(XattrAnswer::Size(size));
+        }
+
+        fn data(self, data: &[u8]) {
+            *self.0 = Some(XattrAnswer::Data(data.to_vec()));
+        }
+
+        fn error(self, err: c_int) {
+            *self.0 = Some(XattrAnswer::Error(err));
+        }
+    }
+
+    #[test]
+    fn serialized_structure_reads_back() {
+        let mut fuse = FuseStructure::deserialize(BlobData::Owned(structure().serialize())).unwrap();
+        assert_eq!(fuse.find_child(1, "file"), Some(FILE_NODE));
+        assert_eq!(read(&mut fuse, FILE_NODE, 0, 30000).unwrap(), contents());
+    }
+
+    #[test]
+    fn symlinks_read_back_as_symlinks() {
+        let mut fuse = structure();
+        fuse.symlinks.push(FuseSymlink {
+            name: "link".to_owned(),
+            node: 4,
+            target: "file".to_owned(),
+        });
+        fuse.directories[0].nodes.push(4);
+        fuse.directories[0].node_types.push(NODE_SYMLINK);
+        fuse.attributes.push(attribute(4, FileType::Symlink, 4));
+
+        let fuse = FuseStructure::deserialize(BlobData::Owned(fuse.serialize())).unwrap();
+        assert_eq!(fuse.find_child(1, "link"), Some(4));
+        assert_eq!(fuse.find_symlink(4).unwrap().target, "file");
+        assert_eq!(fuse.find_attribute(4).unwrap().kind, FileType::Symlink);
+    }
+
+    #[test]
+    fn hard_links_and_shared_contents_read_back() {
+        let mut fuse = structure();
+        // a second file with the same contents and a hard link to the first under another name
+        let mut copy = fuse.files[0].clone();
+        copy.name = "copy".to_owned();
+        copy.node = 4;
+        fuse.files.push(copy);
+        fuse.directories[0].nodes.extend(vec!(4, FILE_NODE));
+        fuse.directories[0].node_types.extend(vec!(NODE_FILE, NODE_FILE));
+        fuse.directories[0].names = vec!("file".to_owned(), "copy".to_owned(), "link".to_owned());
+        fuse.attributes.push(attribute(4, FileType::RegularFile, contents().len() as u64));
+        fuse.build_index();
+        fuse.count_links();
+
+        let blob = fuse.serialize();
+        let mut fuse = FuseStructure::deserialize(BlobData::Owned(blob)).unwrap();
+        assert_eq!(fuse.files[0].data_offset, fuse.files[1].data_offset);
+        assert_eq!(fuse.find_child(1, "link"), Some(FILE_NODE));
+        assert_eq!(fuse.find_attribute(FILE_NODE).unwrap().nlink, 2);
+        assert_eq!(fuse.find_attribute(4).unwrap().nlink, 1);
+        assert_eq!(read(&mut fuse, 4, 0, 100).unwrap(), &contents()[..100]);
+    }
+
+    #[test]
+    fn every_attribute_field_reads_back() {
+        let mut fuse = structure();
+        let attribute = &mut fuse.attributes[1];
+        attribute.blocks = 40;
+        attribute.crtime = Timespec::new(1234, 5678);
+        attribute.nlink = 1;
+        attribute.uid = 1000;
+        attribute.gid = 100;
+        attribute.rdev = 7;
+        attribute.flags = 3;
+        let expected = *attribute;
+
+        let mut fuse = FuseStructure::deserialize(BlobData::Owned(fuse.serialize())).unwrap();
+        let read_back = *fuse.find_attribute(FILE_NODE).unwrap();
+        assert_eq!(format!("{:?}", read_back), format!("{:?}", expected));
+
+        fuse.apply_ownership(Ownership::Preserve);
+        assert_eq!(fuse.find_attribute(FILE_NODE).unwrap().uid, 1000);
+        fuse.apply_ownership(Ownership::from_name("0:0").unwrap());
+        assert_eq!(fuse.find_attribute(FILE_NODE).unwrap().uid, 0);
+        assert_eq!(fuse.find_attribute(ROOT_NODE).unwrap().gid, 0);
+        assert_eq!(fuse.find_attribute(1).unwrap().ino, ROOT_NODE);
+    }
+
+    #[test]
+    fn special_files_keep_their_kind_and_rdev() {
+        let mut fuse = structure();
+        let kinds = [FileType::NamedPipe, FileType::CharDevice, FileType::BlockDevice, FileType::Socket];
+        for (i, kind) in kinds.iter().enumerate() {
+            let node = 4 + i as u64;
+            fuse.specials.push(FuseSpecial {
+                name: format!("special{}", i),
+                node,
+                kind: *kind,
+            });
+            fuse.directories[0].nodes.push(node);
+            fuse.directories[0].node_types.push(NODE_SPECIAL);
+            let mut attribute = attribute(node, *kind, 0);
+            attribute.rdev = 259;
+            fuse.attributes.push(attribute);
+        }
+
+        let fuse = FuseStructure::deserialize(BlobData::Owned(fuse.serialize())).unwrap();
+        for (i, kind) in kinds.iter().enumerate() {
+            let node = fuse.find_child(1, &format!("special{}", i)).unwrap();
+            assert_eq!(fuse.find_entry(node, NODE_SPECIAL).unwrap().1, *kind);
+            assert_eq!(fuse.find_attribute(node).unwrap().kind, *kind);
+            assert_eq!(fuse.find_attribute(node).unwrap().rdev, 259);
+        }
+    }
+
+    #[test]
+    fn manifest_reads_back_and_names_the_entrypoint() {
+        let mut fuse = structure();
+        fuse.manifest.name = "demo".to_owned();
+        fuse.manifest.entrypoint = "file".to_owned();
+        fuse.manifest.args = vec!("--flag".to_owned(), "two words".to_owned());
+        fuse.manifest.workdir = ".".to_owned();
+        fuse.manifest.env.push(("MODE".to_owned(), "packaged".to_owned()));
+        fuse.manifest.labels.push(("maintainer".to_owned(), "someone=else".to_owned()));
+        let expected = fuse.manifest.clone();
+
+        let fuse = FuseStructure::deserialize(BlobData::Owned(fuse.serialize())).unwrap();
+        assert_eq!(fuse.manifest, expected);
+        assert_eq!(fuse.find_path(&fuse.manifest.entrypoint), Some(FILE_NODE));
+        assert_eq!(fuse.find_path("./file"), Some(FILE_NODE));
+        assert_eq!(fuse.find_path("missing"), None);
+    }
+
+    #[test]
+    fn xattrs_are_served_by_name_and_size() {
+        let mut fuse = structure();
+        fuse.xattrs.insert(FILE_NODE, vec!(("user.comment".to_owned(), b"hello".to_vec()), ("security.capability".to_owned(), vec!(1, 2))));
+        let fuse = FuseStructure::deserialize(BlobData::Owned(fuse.serialize())).unwrap();
+
+        let get = |ino: u64, name: &str, size: u32| {
+            let mut answer = None;
+            fuse.get_xattr(ino, name, size, FakeXattrReply(&mut answer));
+            answer.unwrap()
+        };
+        assert_eq!(get(FILE_NODE, "user.comment", 0), XattrAnswer::Size(5));
+        assert_eq!(get(FILE_NODE, "user.comment", 5), XattrAnswer::Data(b"hello".to_vec()));
+        assert_eq!(get(FILE_NODE, "user.comment", 4), XattrAnswer::Error(ERANGE));
+        assert_eq!(get(FILE_NODE, "user.missing", 16), XattrAnswer::Error(ENODATA));
+        assert_eq!(get(ROOT_NODE, "user.comment", 16), XattrAnswer::Error(ENODATA));
+        assert_eq!(get(99, "user.comment", 16), XattrAnswer::Error(ENOENT));
+
+        let mut answer = None;
+        fuse.list_xattrs(FILE_NODE, 64, FakeXattrReply(&mut answer));
+        assert_eq!(answer.unwrap(), XattrAnswer::Data(b"user.comment\0security.capability\0".to_vec()));
+        let mut answer = None;
+        fuse.list_xattrs(ROOT_NODE, 0, FakeXattrReply(&mut answer));
+        assert_eq!(answer.unwrap(), XattrAnswer::Size(0));
+    }
+
+    // the layout before the rpackv header: counts, directories, files with their contents inline, short attributes.
+    // The directory's name is at 38 and its node table at 50, the file's name at 84, its size at 96 and its contents at 104
+    fn legacy_blob() -> Vec<u8> {
+        let root = FuseDirectory { name: "root".to_owned(), nodes: vec!(FILE_NODE), node_types: vec!(NODE_FILE), node: ROOT_NODE, is_root: true, parent_node: 1, names: vec!() };
+        let mut returned: Vec<u8> = LEGACY_MAGIC.to_vec();
+        returned.extend(1u64.to_be_bytes().to_vec());
+        returned.extend(1u64.to_be_bytes().to_vec());
+        returned.extend(2u64.to_be_bytes().to_vec());
+        returned.extend(root.serialize());
+        returned.extend(4u64.to_be_bytes().to_vec());
+        returned.extend(b"file".to_vec());
+        returned.extend(FILE_NODE.to_be_bytes().to_vec());
+        returned.extend(5u64.to_be_bytes().to_vec());
+        returned.extend(b"hello".to_vec());
+        returned.extend(attribute(ROOT_NODE, FileType::Directory, 0).serialize());
+        returned.extend(attribute(FILE_NODE, FileType::RegularFile, 5).serialize());
+        returned
+    }
+
+    fn deserialize_error(blob: Vec<u8>) -> Option<BlobError> {
+        FuseStructure::deserialize(BlobData::Owned(blob)).err()
+    }
+
+    #[test]
+    fn legacy_blob_reads_back() {
+        let mut fuse = FuseStructure::deserialize(BlobData::Owned(legacy_blob())).unwrap();
+        assert_eq!(fuse.find_child(1, "file"), Some(FILE_NODE));
+        assert_eq!(read(&mut fuse, FILE_NODE, 0, 4096).unwrap(), b"hello");
+        let attribute = fuse.find_attribute(FILE_NODE).unwrap();
+        assert_eq!((attribute.uid, attribute.gid, attribute.nlink), (LEGACY_UID, LEGACY_GID, 1));
+
+        // without the root's attribute record, the 55 bytes at 109, the root is made up
+        let mut blob = legacy_blob();
+        blob[22..30].copy_from_slice(&1u64.to_be_bytes());
+        blob.drain(109..164);
+        let fuse = FuseStructure::deserialize(BlobData::Owned(blob)).unwrap();
+        let root = fuse.find_attribute(1).unwrap();
+        assert_eq!((root.ino, root.kind, root.perm, root.uid), (ROOT_NODE, FileType::Directory, 0o755, LEGACY_UID));
+    }
+
+    #[test]
+    fn corrupt_blobs_report_what_and_where() {
+        let mut blob = legacy_blob();
+        blob.truncate(100);
+        assert_eq!(deserialize_error(blob), Some(BlobError::Truncated { offset: 96, needed: 8 }));
+
+        // the section count of an rpackv header is at 20
+        let mut blob = structure().serialize();
+        blob.truncate(22);
+        assert_eq!(deserialize_error(blob), Some(BlobError::Truncated { offset: 20, needed: 4 }));
+
+        let mut blob = structure().serialize();
+        blob[0] = b'x';
+        assert_eq!(deserialize_error(blob), Some(BlobError::BadMagic));
+
+        let mut blob = legacy_blob();
+        blob[84] = 0xff;
+        assert_eq!(deserialize_error(blob), Some(BlobError::InvalidUtf8Name { offset: 84 }));
+
+        let mut blob = legacy_blob();
+        blob[58..66].copy_from_slice(&9u64.to_be_bytes());
+        assert_eq!(deserialize_error(blob), Some(BlobError::DanglingNode { node: 9 }));
+
+        let mut blob = legacy_blob();
+        blob[50..58].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+        assert_eq!(deserialize_error(blob), Some(BlobError::LengthOverflow { offset: 50 }));
+
+        // the root's attribute record starts at 109, its kind is its last byte
+        let mut blob = legacy_blob();
+        blob[163] = 7;
+        assert_eq!(deserialize_error(blob), Some(BlobError::UnknownKind { offset: 163 }));
+    }
+
+    #[test]
+    fn unknown_header_flags_are_refused() {
+        let mut blob = structure().serialize();
+        blob[11] |= 1;
+        assert_eq!(deserialize_error(blob), Some(BlobError::UnsupportedFlags(1)));
+    }
+}